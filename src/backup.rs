@@ -0,0 +1,349 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::{copy, create_dir_all, read_to_string, remove_dir_all, remove_file, rename, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use thiserror::Error;
+
+use crate::{
+    deletefiles, hdiffmap, out_of_place,
+    paths::{self, GameDir, PathError},
+};
+
+pub const BACKUP_DIR_NAME: &str = "hdiff-apply-backup";
+
+/// Files that didn't exist before the update, one manifest path per line
+const CREATED_LIST_NAME: &str = ".created-files";
+
+/// Client version the snapshot was taken from
+const VERSION_FILE_NAME: &str = ".version";
+
+/// Written once every package of the update is applied, holds the version it produced
+const FINISHED_MARKER_NAME: &str = ".finished";
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("Failed to back up {0}: {1}")]
+    Save(String, #[source] std::io::Error),
+    #[error("Failed to restore {0}: {1}")]
+    Restore(String, #[source] std::io::Error),
+    #[error("{0} has no backup")]
+    Missing(String),
+    #[error("There is no backup to roll back to in {0}")]
+    NoSnapshot(String),
+    #[error("{0} holds a backup of unknown origin, run `hdiff-apply rollback` or remove it first")]
+    UnknownSnapshot(String),
+    #[error("An update from {1} didn't finish and left the client on {2}, run `hdiff-apply rollback` to restore {1} from {0} first")]
+    Unfinished(String, String, String),
+    #[error("An update from {1} didn't finish, run `hdiff-apply rollback` to restore it from {0} or update with --backup affected to keep that backup")]
+    UnfinishedWithoutBackup(String, String),
+    #[error("The backup in {0} is of the update to {1} but the client is on {2} now, rolling back would mix both versions")]
+    VersionChanged(String, String, String),
+    #[error(transparent)]
+    Path(#[from] PathError),
+}
+
+/// How a file was saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveKind {
+    /// Copy-on-write clone, takes no extra space until the original changes
+    Reflink,
+    Copy,
+    /// Didn't exist yet, removed again on rollback
+    Created,
+    /// Already saved earlier in this run, or nothing to save
+    Skipped,
+}
+
+#[derive(Debug, Default)]
+pub struct RollbackReport {
+    pub restored: usize,
+    pub removed: usize,
+    /// Directories removed because only the update's files were in them
+    pub pruned_dirs: usize,
+}
+
+/// Snapshot of game files taken before an update modifies them, mirroring the
+/// game directory layout under `<game>/hdiff-apply-backup`. Files are reflinked
+/// where the filesystem supports it and copied otherwise. Files the update creates
+/// are recorded so a rollback can remove them
+pub struct Backup {
+    game_dir: GameDir,
+    backup_path: PathBuf,
+    created: Mutex<HashSet<String>>,
+}
+
+impl Backup {
+    pub fn new(game_dir: &GameDir) -> Self {
+        let backup_path = game_dir.path().join(BACKUP_DIR_NAME);
+        let created = read_to_string(backup_path.join(CREATED_LIST_NAME))
+            .map(|list| list.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+
+        Self {
+            game_dir: game_dir.clone(),
+            backup_path,
+            created: Mutex::new(created),
+        }
+    }
+
+    /// Where the saved copy of `name` is kept
+    pub fn backup_file(&self, name: &str) -> Result<PathBuf, PathError> {
+        Ok(self.backup_path.join(paths::normalize(name)?))
+    }
+
+    pub fn exists(&self) -> bool {
+        self.backup_path.is_dir()
+    }
+
+    /// Start a snapshot of the client on `version`. The snapshot of a finished update is
+    /// dropped. An unfinished one still holds the files from before that update: it is kept
+    /// when the client is on the same version, so the update can run again, and otherwise
+    /// nothing continues until it is rolled back
+    pub fn begin(&self, version: &str) -> Result<(), BackupError> {
+        let display = self.backup_path.display().to_string();
+        let version_path = self.backup_path.join(VERSION_FILE_NAME);
+
+        if let Some(saved) = self.unfinished_from()? {
+            if saved != version {
+                return Err(BackupError::Unfinished(display, saved, version.to_string()));
+            }
+
+            tracing::warn!(
+                "Keeping the backup of an unfinished update from {} in {}",
+                version,
+                self.backup_path.display()
+            );
+            return Ok(());
+        }
+
+        self.clear()
+            .and_then(|_| create_dir_all(&self.backup_path))
+            .and_then(|_| std::fs::write(&version_path, version))
+            .map_err(|e| BackupError::Save(display, e))
+    }
+
+    /// Version an unfinished snapshot was taken from, `None` without one
+    fn unfinished_from(&self) -> Result<Option<String>, BackupError> {
+        if !self.exists() || self.backup_path.join(FINISHED_MARKER_NAME).exists() {
+            return Ok(None);
+        }
+
+        let display = self.backup_path.display().to_string();
+        match read_to_string(self.backup_path.join(VERSION_FILE_NAME)) {
+            Ok(saved) => Ok(Some(saved.trim().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(BackupError::UnknownSnapshot(display)),
+            Err(e) => Err(BackupError::Save(display, e)),
+        }
+    }
+
+    /// Mark the update as applied, recording the version it produced. The next update
+    /// starts a new snapshot
+    pub fn finish(&self, version: &str) -> Result<(), BackupError> {
+        std::fs::write(self.backup_path.join(FINISHED_MARKER_NAME), version)
+            .map_err(|e| BackupError::Save(self.backup_path.display().to_string(), e))
+    }
+
+    /// Drop the snapshot of a finished update before updating without a backup, it would
+    /// roll the client back onto the wrong version afterwards. An unfinished one still holds
+    /// files nothing else has, so it is never dropped implicitly
+    pub fn discard(&self) -> Result<(), BackupError> {
+        let path = self.backup_path.display().to_string();
+        if let Some(saved) = self.unfinished_from()? {
+            return Err(BackupError::UnfinishedWithoutBackup(path, saved));
+        }
+
+        if self.exists() {
+            tracing::info!("Removing the backup of the previous update from {}", path);
+            self.clear().map_err(|e| BackupError::Save(path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Drop the backup of a previous run
+    pub fn clear(&self) -> Result<(), std::io::Error> {
+        if self.backup_path.exists() {
+            remove_dir_all(&self.backup_path)?;
+        }
+        self.created.lock().unwrap().clear();
+        Ok(())
+    }
+
+    /// Back up `name` unless an older copy is already saved, so the backup always holds
+    /// the state from before the run. Directories are saved file by file and paths that
+    /// don't exist yet are recorded as created
+    pub fn save(&self, name: &str) -> Result<SaveKind, BackupError> {
+        let name = paths::manifest_name(name);
+        let source = self.game_dir.resolve(&name)?;
+        let backup = self.backup_file(&name)?;
+        let save_error = |e| BackupError::Save(name.clone(), e);
+
+        if backup.is_file() || self.created.lock().unwrap().contains(&name) {
+            return Ok(SaveKind::Skipped);
+        }
+
+        let Ok(metadata) = source.symlink_metadata() else {
+            self.record_created(&name).map_err(save_error)?;
+            return Ok(SaveKind::Created);
+        };
+
+        if metadata.is_dir() {
+            for entry in source.read_dir().map_err(save_error)? {
+                let entry = entry.map_err(save_error)?;
+                self.save(&format!("{}/{}", name, entry.file_name().to_string_lossy()))?;
+            }
+            return Ok(SaveKind::Skipped);
+        }
+
+        if let Some(parent) = backup.parent() {
+            create_dir_all(parent).map_err(save_error)?;
+        }
+
+        let kind = match out_of_place::reflink(&source, &backup) {
+            Ok(()) => SaveKind::Reflink,
+            Err(_) => {
+                copy(&source, &backup).map_err(save_error)?;
+                SaveKind::Copy
+            }
+        };
+
+        tracing::debug!("Backed up {} ({:?})", source.display(), kind);
+        Ok(kind)
+    }
+
+    fn record_created(&self, name: &str) -> std::io::Result<()> {
+        let mut created = self.created.lock().unwrap();
+        if !created.insert(name.to_string()) {
+            return Ok(());
+        }
+
+        create_dir_all(&self.backup_path)?;
+        let mut list = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.backup_path.join(CREATED_LIST_NAME))?;
+        writeln!(list, "{}", name)
+    }
+
+    /// Manifest paths that didn't exist before the update
+    pub fn created_files(&self) -> Vec<String> {
+        let mut created: Vec<String> = self.created.lock().unwrap().iter().cloned().collect();
+        created.sort();
+        created
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.backup_file(name).is_ok_and(|backup| backup.is_file())
+    }
+
+    /// Copy the saved version of `name` back into the game directory
+    pub fn restore(&self, name: &str) -> Result<(), BackupError> {
+        self.restore_to(name, &self.game_dir.resolve(name)?)
+    }
+
+    /// Copy the saved version of `name` to `target`
+    pub fn restore_to(&self, name: &str, target: &Path) -> Result<(), BackupError> {
+        let backup = self.backup_file(name)?;
+        if !backup.is_file() {
+            return Err(BackupError::Missing(name.to_string()));
+        }
+
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent).map_err(|e| BackupError::Restore(name.to_string(), e))?;
+        }
+        // Replace the file instead of writing into it, it may be a hardlink to another install,
+        // and rename it into place so nothing reading the target sees a partial copy
+        let temp = hdiffmap::temp_target_path(target);
+        copy(&backup, &temp)
+            .and_then(|_| rename(&temp, target))
+            .map_err(|e| {
+                let _ = remove_file(&temp);
+                BackupError::Restore(name.to_string(), e)
+            })?;
+
+        tracing::info!("Restored {} from backup", target.display());
+        Ok(())
+    }
+
+    /// Manifest paths of every saved file
+    pub fn saved_files(&self) -> Result<Vec<String>, BackupError> {
+        let mut files = vec![];
+        let mut pending = vec![PathBuf::new()];
+
+        while let Some(relative) = pending.pop() {
+            let dir = self.backup_path.join(&relative);
+            let list_error = |e| BackupError::Restore(dir.display().to_string(), e);
+
+            for entry in dir.read_dir().map_err(list_error)? {
+                let entry = entry.map_err(list_error)?;
+                let path = relative.join(entry.file_name());
+
+                if entry.file_type().map_err(list_error)?.is_dir() {
+                    pending.push(path);
+                } else if ![CREATED_LIST_NAME, VERSION_FILE_NAME, FINISHED_MARKER_NAME]
+                    .iter()
+                    .any(|bookkeeping| path == Path::new(bookkeeping))
+                {
+                    files.push(paths::manifest_name(&path.to_string_lossy()));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Put the game directory back into the state before the update: restore every
+    /// saved file, remove the files the update created along with the directories they
+    /// leave empty and drop the backup. `version` is the client's current version, which
+    /// has to be the one the update produced unless the update didn't finish
+    pub fn rollback(&self, version: &str) -> Result<RollbackReport, BackupError> {
+        if !self.exists() {
+            return Err(BackupError::NoSnapshot(
+                self.game_dir.path().display().to_string(),
+            ));
+        }
+
+        let display = self.backup_path.display().to_string();
+        match read_to_string(self.backup_path.join(FINISHED_MARKER_NAME)) {
+            Ok(updated) if updated.trim() != version => {
+                return Err(BackupError::VersionChanged(
+                    display,
+                    updated.trim().to_string(),
+                    version.to_string(),
+                ))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(BackupError::Restore(display, e)),
+        }
+
+        let mut report = RollbackReport::default();
+        let mut parents = BTreeSet::new();
+
+        for name in self.created_files() {
+            let path = self.game_dir.resolve(&name)?;
+            match remove_file(&path) {
+                Ok(()) => report.removed += 1,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(BackupError::Restore(name, e)),
+            }
+            if let Some(parent) = path.parent() {
+                parents.insert(parent.to_path_buf());
+            }
+        }
+
+        for name in self.saved_files()? {
+            self.restore(&name)?;
+            report.restored += 1;
+        }
+        // Restored files recreate their directories, so only the update's own ones are left empty
+        report.pruned_dirs = deletefiles::prune_empty_dirs(self.game_dir.path(), parents);
+
+        self.clear()
+            .map_err(|e| BackupError::Restore(self.backup_path.display().to_string(), e))?;
+        Ok(report)
+    }
+}
@@ -1,7 +1,10 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{fmt, fs::File, io::Read, path::PathBuf};
 
 use crate::Error;
 
+/// Location of the version file inside the game directory and update archives
+pub const BINARY_VERSION_PATH: &str = "StarRail_Data/StreamingAssets/BinaryVersion.bytes";
+
 #[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
 pub struct BinaryVersion {
     pub major_version: u32,
@@ -17,7 +20,7 @@ impl BinaryVersion {
 
         let content = String::from_utf8_lossy(&buf[..n]);
 
-        let dash_pos = content.rfind('-').unwrap();
+        let dash_pos = content.rfind('-').ok_or(Error::VersionParse())?;
         let start_pos = dash_pos.saturating_sub(6);
         let version_slice: &str = &content[start_pos..];
 
@@ -42,9 +45,10 @@ impl BinaryVersion {
     }
 }
 
-impl ToString for BinaryVersion {
-    fn to_string(&self) -> String {
-        format!(
+impl fmt::Display for BinaryVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "{}.{}.{}",
             self.major_version, self.minor_version, self.patch_version
         )
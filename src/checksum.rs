@@ -0,0 +1,124 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ChecksumError {
+    #[error("Checksum mismatch for {file}: expected {expected}, got {actual}")]
+    Mismatch {
+        file: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Invalid checksum file {0}: no hash found")]
+    InvalidSidecar(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn extension(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha256 => "sha256",
+        }
+    }
+
+    fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha256 => 64,
+        }
+    }
+}
+
+/// Look for `<archive>.md5`/`<archive>.sha256` (or the same with the archive extension
+/// replaced) next to the archive
+pub fn find_sidecar(archive: &Path) -> Option<(Algorithm, PathBuf)> {
+    for algorithm in [Algorithm::Sha256, Algorithm::Md5] {
+        let mut appended = archive.as_os_str().to_owned();
+        appended.push(".");
+        appended.push(algorithm.extension());
+
+        let candidates = [
+            PathBuf::from(appended),
+            archive.with_extension(algorithm.extension()),
+        ];
+
+        if let Some(path) = candidates.into_iter().find(|p| p.is_file()) {
+            return Some((algorithm, path));
+        }
+    }
+
+    None
+}
+
+pub fn hash_file(path: &Path, algorithm: Algorithm) -> Result<String, ChecksumError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = vec![0; 1024 * 1024];
+
+    match algorithm {
+        Algorithm::Md5 => {
+            let mut hasher = Md5::new();
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        Algorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+/// Verify the archive against its sidecar checksum file if there is one.
+/// Returns `Ok(false)` when no sidecar exists
+pub fn verify_sidecar(archive: &Path) -> Result<bool, ChecksumError> {
+    let Some((algorithm, sidecar)) = find_sidecar(archive) else {
+        return Ok(false);
+    };
+
+    // Accepts both a bare hash and the `<hash>  <file name>` format of md5sum/sha256sum
+    let content = std::fs::read_to_string(&sidecar)?;
+    let expected = content
+        .split_whitespace()
+        .next()
+        .map(|s| s.trim_start_matches('\\').to_lowercase())
+        .filter(|s| s.len() == algorithm.hex_len() && s.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| ChecksumError::InvalidSidecar(sidecar.display().to_string()))?;
+
+    let actual = hash_file(archive, algorithm)?;
+    if actual != expected {
+        return Err(ChecksumError::Mismatch {
+            file: archive.display().to_string(),
+            expected,
+            actual,
+        });
+    }
+
+    Ok(true)
+}
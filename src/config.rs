@@ -0,0 +1,457 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{utils, TEMP_DIR_NAME};
+
+pub const CONFIG_FILE_NAME: &str = "hdiff-apply.toml";
+const ENV_PREFIX: &str = "HDIFF_APPLY_";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to parse config file {0}: {1}")]
+    Parse(String, String),
+    #[error("Invalid value '{1}' for environment variable {0}")]
+    InvalidEnv(String, String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyLevel {
+    /// Skip all pre-flight checks
+    None,
+    /// Test the archives and compare source file sizes
+    #[default]
+    Size,
+    /// Also compare the MD5 of every source file the manifest has one for
+    Md5,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MismatchPolicy {
+    /// Don't update anything
+    #[default]
+    Abort,
+    /// Leave the entries with bad sources out and patch the rest
+    Skip,
+    /// List the bad sources and ask whether to skip them
+    Prompt,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupPolicy {
+    /// Don't back up anything
+    #[default]
+    None,
+    /// Snapshot every file the update is going to patch, overwrite or delete, using
+    /// reflinks where the filesystem supports them
+    Affected,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Stop before applying the next package
+    #[default]
+    Abort,
+    /// Log the failed entries and keep going
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RunningGamePolicy {
+    /// Refuse to update
+    #[default]
+    Abort,
+    /// Wait until the game and launcher are closed
+    Wait,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    #[default]
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    const ORDER: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    /// Move the level up (positive) or down (negative) by `steps`, used by `-v`/`-q`
+    pub fn adjust(self, steps: i8) -> Self {
+        let index = Self::ORDER.iter().position(|l| *l == self).unwrap_or(0) as i8;
+        let index = (index + steps).clamp(0, Self::ORDER.len() as i8 - 1);
+        Self::ORDER[index as usize]
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogConfig {
+    /// Console log level, the log file always records at least debug
+    pub level: LogLevel,
+    /// Write a log file, by default `hdiff-apply.log` in the game directory
+    pub file: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Format of the log file
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::default(),
+            file: true,
+            path: None,
+            format: LogFormat::default(),
+        }
+    }
+}
+
+/// Effective configuration after merging every layer
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub game_path: Option<PathBuf>,
+    pub temp_dir: PathBuf,
+    /// Write the updated client here instead of updating the game directory in place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+    /// hpatchz to use instead of the embedded one or the one in PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hpatchz_path: Option<PathBuf>,
+    /// 7-Zip to use instead of the embedded one or the one in PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sevenzip_path: Option<PathBuf>,
+    /// Write a package downgrading back to the current version into this directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reverse_dir: Option<PathBuf>,
+    /// hdiffz used to build the reverse package instead of the one in PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hdiffz_path: Option<PathBuf>,
+    /// Worker thread count, 0 means one per CPU core
+    pub jobs: usize,
+    /// Total size in MiB of patch sources processed at once, 0 means half of the system memory
+    pub memory_budget: u64,
+    /// How many times a failed patch entry is retried
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds, doubled for every further attempt
+    pub retry_delay_ms: u64,
+    pub verify: VerifyLevel,
+    /// What to do when pre-flight verification finds missing or modified source files
+    pub on_mismatch: MismatchPolicy,
+    pub backup: BackupPolicy,
+    /// What to do when a package has entries that failed to patch
+    pub on_failure: FailurePolicy,
+    /// What to do when the game or launcher is running or has files of the install open
+    pub running_game: RunningGamePolicy,
+    /// Answer used when the confirmation prompt is left empty
+    pub confirm_default: bool,
+    pub log: LogConfig,
+    /// Config files that were loaded, lowest precedence first
+    #[serde(skip)]
+    pub sources: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            game_path: None,
+            temp_dir: env::temp_dir().join(TEMP_DIR_NAME),
+            output_dir: None,
+            hpatchz_path: None,
+            sevenzip_path: None,
+            reverse_dir: None,
+            hdiffz_path: None,
+            jobs: 0,
+            memory_budget: 0,
+            retries: 2,
+            retry_delay_ms: 1000,
+            verify: VerifyLevel::default(),
+            on_mismatch: MismatchPolicy::default(),
+            backup: BackupPolicy::default(),
+            on_failure: FailurePolicy::default(),
+            running_game: RunningGamePolicy::default(),
+            confirm_default: true,
+            log: LogConfig::default(),
+            sources: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogLayer {
+    pub level: Option<LogLevel>,
+    pub file: Option<bool>,
+    pub path: Option<PathBuf>,
+    pub format: Option<LogFormat>,
+}
+
+/// One source of configuration, every value is optional so layers can be stacked
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub game_path: Option<PathBuf>,
+    pub temp_dir: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>,
+    pub hpatchz_path: Option<PathBuf>,
+    pub sevenzip_path: Option<PathBuf>,
+    pub reverse_dir: Option<PathBuf>,
+    pub hdiffz_path: Option<PathBuf>,
+    pub jobs: Option<usize>,
+    pub memory_budget: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub verify: Option<VerifyLevel>,
+    pub on_mismatch: Option<MismatchPolicy>,
+    pub backup: Option<BackupPolicy>,
+    pub on_failure: Option<FailurePolicy>,
+    pub running_game: Option<RunningGamePolicy>,
+    pub confirm_default: Option<bool>,
+    pub log: LogLayer,
+}
+
+impl ConfigLayer {
+    pub fn from_file(path: &Path) -> Result<Option<Self>, ConfigError> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let data = std::fs::read_to_string(path)?;
+        toml::from_str(&data)
+            .map(Some)
+            .map_err(|e| ConfigError::Parse(path.display().to_string(), e.to_string()))
+    }
+
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            game_path: env_var("GAME_PATH").map(PathBuf::from),
+            temp_dir: env_var("TEMP_DIR").map(PathBuf::from),
+            output_dir: env_var("OUTPUT_DIR").map(PathBuf::from),
+            hpatchz_path: env_var("HPATCHZ_PATH").map(PathBuf::from),
+            sevenzip_path: env_var("SEVENZIP_PATH").map(PathBuf::from),
+            reverse_dir: env_var("REVERSE_DIR").map(PathBuf::from),
+            hdiffz_path: env_var("HDIFFZ_PATH").map(PathBuf::from),
+            jobs: parse_env("JOBS", |v| usize::from_str(v).ok())?,
+            memory_budget: parse_env("MEMORY_BUDGET", |v| u64::from_str(v).ok())?,
+            retries: parse_env("RETRIES", |v| u32::from_str(v).ok())?,
+            retry_delay_ms: parse_env("RETRY_DELAY_MS", |v| u64::from_str(v).ok())?,
+            verify: parse_env("VERIFY", |v| VerifyLevel::from_str(v, true).ok())?,
+            on_mismatch: parse_env("ON_MISMATCH", |v| MismatchPolicy::from_str(v, true).ok())?,
+            backup: parse_env("BACKUP", |v| BackupPolicy::from_str(v, true).ok())?,
+            on_failure: parse_env("ON_FAILURE", |v| FailurePolicy::from_str(v, true).ok())?,
+            running_game: parse_env("RUNNING_GAME", |v| {
+                RunningGamePolicy::from_str(v, true).ok()
+            })?,
+            confirm_default: parse_env("CONFIRM_DEFAULT", parse_bool)?,
+            log: LogLayer {
+                level: parse_env("LOG_LEVEL", |v| LogLevel::from_str(v, true).ok())?,
+                file: parse_env("LOG_TO_FILE", parse_bool)?,
+                path: env_var("LOG_FILE").map(PathBuf::from),
+                format: parse_env("LOG_FORMAT", |v| LogFormat::from_str(v, true).ok())?,
+            },
+        })
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(format!("{ENV_PREFIX}{name}"))
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+fn parse_env<T>(name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, ConfigError> {
+    match env_var(name) {
+        Some(value) => parse(value.trim())
+            .map(Some)
+            .ok_or_else(|| ConfigError::InvalidEnv(format!("{ENV_PREFIX}{name}"), value)),
+        None => Ok(None),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "y" => Some(true),
+        "0" | "false" | "no" | "n" => Some(false),
+        _ => None,
+    }
+}
+
+impl Config {
+    /// Per-user config file, `%APPDATA%\hdiff-apply\hdiff-apply.toml` on Windows
+    /// and `$XDG_CONFIG_HOME/hdiff-apply/hdiff-apply.toml` elsewhere
+    pub fn user_config_path() -> Option<PathBuf> {
+        let base = if cfg!(target_os = "windows") {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        };
+
+        base.map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(CONFIG_FILE_NAME))
+    }
+
+    pub fn apply(&mut self, layer: ConfigLayer) {
+        if let Some(game_path) = layer.game_path {
+            self.game_path = Some(game_path);
+        }
+        if let Some(temp_dir) = layer.temp_dir {
+            self.temp_dir = temp_dir;
+        }
+        if let Some(output_dir) = layer.output_dir {
+            self.output_dir = Some(output_dir);
+        }
+        if let Some(hpatchz_path) = layer.hpatchz_path {
+            self.hpatchz_path = Some(hpatchz_path);
+        }
+        if let Some(sevenzip_path) = layer.sevenzip_path {
+            self.sevenzip_path = Some(sevenzip_path);
+        }
+        if let Some(reverse_dir) = layer.reverse_dir {
+            self.reverse_dir = Some(reverse_dir);
+        }
+        if let Some(hdiffz_path) = layer.hdiffz_path {
+            self.hdiffz_path = Some(hdiffz_path);
+        }
+        if let Some(jobs) = layer.jobs {
+            self.jobs = jobs;
+        }
+        if let Some(memory_budget) = layer.memory_budget {
+            self.memory_budget = memory_budget;
+        }
+        if let Some(retries) = layer.retries {
+            self.retries = retries;
+        }
+        if let Some(retry_delay_ms) = layer.retry_delay_ms {
+            self.retry_delay_ms = retry_delay_ms;
+        }
+        if let Some(verify) = layer.verify {
+            self.verify = verify;
+        }
+        if let Some(on_mismatch) = layer.on_mismatch {
+            self.on_mismatch = on_mismatch;
+        }
+        if let Some(backup) = layer.backup {
+            self.backup = backup;
+        }
+        if let Some(on_failure) = layer.on_failure {
+            self.on_failure = on_failure;
+        }
+        if let Some(running_game) = layer.running_game {
+            self.running_game = running_game;
+        }
+        if let Some(confirm_default) = layer.confirm_default {
+            self.confirm_default = confirm_default;
+        }
+        if let Some(level) = layer.log.level {
+            self.log.level = level;
+        }
+        if let Some(file) = layer.log.file {
+            self.log.file = file;
+        }
+        if let Some(path) = layer.log.path {
+            self.log.path = Some(path);
+        }
+        if let Some(format) = layer.log.format {
+            self.log.format = format;
+        }
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(layer) = ConfigLayer::from_file(path)? {
+            self.apply(layer);
+            self.sources.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Merge defaults, the per-user file, the per-game-directory file, `HDIFF_APPLY_*`
+    /// variables and finally the CLI flags, each overriding the previous one.
+    ///
+    /// The game directory file is looked up in the game path resolved from the layers
+    /// above it, or the current directory when none is set
+    pub fn load(cli: ConfigLayer) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+
+        if let Some(user_config) = Self::user_config_path() {
+            config.apply_file(&user_config)?;
+        }
+
+        let env = ConfigLayer::from_env()?;
+        let game_dir = cli
+            .game_path
+            .clone()
+            .or_else(|| env.game_path.clone())
+            .or_else(|| config.game_path.clone())
+            .map_or_else(env::current_dir, Ok)?;
+        config.apply_file(&game_dir.join(CONFIG_FILE_NAME))?;
+
+        config.apply(env);
+        config.apply(cli);
+
+        Ok(config)
+    }
+
+    /// Memory budget in bytes with the automatic default resolved
+    pub fn memory_budget_bytes(&self) -> u64 {
+        const MIB: u64 = 1024 * 1024;
+
+        match self.memory_budget {
+            0 => utils::total_memory().map_or(4096 * MIB, |total| total / 2),
+            mib => mib.saturating_mul(MIB),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+}
@@ -1,33 +1,118 @@
 use std::{
-    fs::{remove_file, File},
-    io::{BufRead, BufReader},
-    path::Path,
+    collections::BTreeSet,
+    fs::{remove_dir, remove_dir_all, remove_file, File},
+    io::{BufRead, BufReader, ErrorKind},
+    path::{Path, PathBuf},
 };
 
 use thiserror::Error;
 
+use crate::paths::{self, GameDir, PathError};
+
 pub struct DeleteFiles<'a> {
-    game_path: &'a Path,
-    count: u32,
+    game_dir: &'a GameDir,
 }
 
 #[derive(Debug, Error)]
 pub enum DeleteFileError {
     #[error("{0} doesn't exist, skipping")]
     NotFound(String),
+    #[error(transparent)]
+    Path(#[from] PathError),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+#[derive(Debug)]
+pub enum DeleteOutcome {
+    /// Removed, `bytes` is the size of the file or everything inside the directory
+    Deleted {
+        bytes: u64,
+    },
+    /// Nothing to do, the file was not there to begin with
+    AlreadyGone,
+    Failed(std::io::Error),
+}
+
+#[derive(Debug)]
+pub struct DeleteResult {
+    pub file_name: String,
+    pub outcome: DeleteOutcome,
+}
+
+#[derive(Debug, Default)]
+pub struct DeleteReport {
+    pub entries: Vec<DeleteResult>,
+    /// Directories removed because the deletion left them empty
+    pub pruned_dirs: usize,
+}
+
+impl DeleteReport {
+    pub fn deleted(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.outcome, DeleteOutcome::Deleted { .. }))
+            .count()
+    }
+
+    pub fn already_gone(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.outcome, DeleteOutcome::AlreadyGone))
+            .count()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &DeleteResult> {
+        self.entries
+            .iter()
+            .filter(|e| matches!(e.outcome, DeleteOutcome::Failed(_)))
+    }
+
+    pub fn freed_bytes(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|e| match e.outcome {
+                DeleteOutcome::Deleted { bytes } => bytes,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+/// Strip CRLF leftovers and surrounding whitespace and use `/` as separator.
+/// Blank lines become `None`
+fn normalize_line(line: &str) -> Option<String> {
+    let line = paths::manifest_name(line);
+    (!line.is_empty()).then_some(line)
+}
+
+/// Total size of a file or of every file below a directory
+fn size_on_disk(path: &Path) -> u64 {
+    let Ok(metadata) = path.symlink_metadata() else {
+        return 0;
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    path.read_dir()
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| size_on_disk(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
 impl<'a> DeleteFiles<'a> {
-    pub fn new(game_path: &'a Path) -> Self {
-        Self {
-            game_path,
-            count: 0,
-        }
+    pub fn new(game_dir: &'a GameDir) -> Self {
+        Self { game_dir }
     }
 
-    pub fn remove(&mut self, deletefiles_path: &Path) -> Result<(), DeleteFileError> {
+    /// Files listed in deletefiles.txt, relative to the game directory
+    pub fn listed_files(&self, deletefiles_path: &Path) -> Result<Vec<String>, DeleteFileError> {
         if !deletefiles_path.exists() {
             return Err(DeleteFileError::NotFound(format!(
                 "{}",
@@ -35,28 +120,93 @@ impl<'a> DeleteFiles<'a> {
             )));
         }
 
-        let file = File::open(&deletefiles_path)?;
-        let reader = BufReader::new(file);
+        let file = File::open(deletefiles_path)?;
+        let mut files = vec![];
+        for line in BufReader::new(file).lines() {
+            files.extend(normalize_line(&line?));
+        }
+        Ok(files)
+    }
+
+    pub fn remove(&self, deletefiles_path: &Path) -> Result<DeleteReport, DeleteFileError> {
+        // Check every path first so a bad entry stops the whole list before anything is deleted
+        let listed = self
+            .listed_files(deletefiles_path)?
+            .into_iter()
+            .map(|name| self.game_dir.resolve(&name).map(|path| (name, path)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut report = DeleteReport::default();
+        let mut parents = BTreeSet::new();
 
-        for line in reader.lines() {
-            let line = line?;
+        for (file_name, full_path) in listed {
+            let outcome = match full_path.symlink_metadata() {
+                Err(e) if e.kind() == ErrorKind::NotFound => DeleteOutcome::AlreadyGone,
+                Err(e) => DeleteOutcome::Failed(e),
+                Ok(metadata) => {
+                    let bytes = size_on_disk(&full_path);
+                    let result = if metadata.is_dir() {
+                        remove_dir_all(&full_path)
+                    } else {
+                        remove_file(&full_path)
+                    };
 
-            let path = Path::new(&line);
-            let full_path = &self.game_path.join(path);
+                    match result {
+                        Ok(()) => DeleteOutcome::Deleted { bytes },
+                        Err(e) => DeleteOutcome::Failed(e),
+                    }
+                }
+            };
 
-            match remove_file(&full_path) {
-                Ok(_) => {
+            match &outcome {
+                DeleteOutcome::Deleted { .. } => {
                     tracing::info!("Deleted {}", full_path.display());
-                    self.count += 1;
+                    if let Some(parent) = full_path.parent() {
+                        parents.insert(parent.to_path_buf());
+                    }
                 }
-                Err(e) => tracing::error!("Failed to delete {}: {}", full_path.display(), e),
+                DeleteOutcome::AlreadyGone => {
+                    tracing::debug!("{} is already gone", full_path.display())
+                }
+                // Reported by the caller together with the summary
+                DeleteOutcome::Failed(_) => {}
             }
+
+            report.entries.push(DeleteResult { file_name, outcome });
         }
 
-        Ok(())
+        report.pruned_dirs = prune_empty_dirs(self.game_dir.path(), parents);
+        Ok(report)
     }
+}
+
+/// Remove directories left empty by a deletion, walking up towards `root`.
+/// Deepest paths come last in the set so they are handled first
+pub fn prune_empty_dirs(root: &Path, dirs: BTreeSet<PathBuf>) -> usize {
+    let mut pruned = 0;
+
+    for dir in dirs.iter().rev() {
+        let mut current = dir.as_path();
+
+        while current != root && current.starts_with(root) {
+            let is_empty = current
+                .read_dir()
+                .map(|mut entries| entries.next().is_none())
+                .unwrap_or(false);
+
+            if !is_empty || remove_dir(current).is_err() {
+                break;
+            }
+
+            tracing::debug!("Removed empty directory {}", current.display());
+            pruned += 1;
 
-    pub fn count(&self) -> u32 {
-        self.count
+            match current.parent() {
+                Some(parent) => current = parent,
+                None => break,
+            }
+        }
     }
+
+    pruned
 }
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashSet,
+    env, fmt,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    binary_version::{BinaryVersion, BINARY_VERSION_PATH},
+    paths,
+};
+
+pub const GAME_EXECUTABLE: &str = "StarRail.exe";
+
+/// How deep below a search root the game directory may be
+const MAX_DEPTH: usize = 4;
+
+/// Where launchers put the game inside a Wine prefix, relative to `drive_c`
+const PREFIX_SEARCH_DIRS: [&str; 3] = ["Program Files", "Program Files (x86)", "Games"];
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("No discovered install matches '{0}', run `hdiff-apply installs` to list them")]
+    NoMatch(String),
+    #[error("'{0}' matches several installs:\n{1}")]
+    Ambiguous(String, String),
+    #[error("Found several installs, pick one with --install <INDEX|NAME>:\n{0}")]
+    Several(String),
+}
+
+/// A game directory found on this system
+#[derive(Debug)]
+pub struct Install {
+    pub path: PathBuf,
+    /// Launcher or Wine prefix it was found in
+    pub source: String,
+    pub version: Option<BinaryVersion>,
+}
+
+impl fmt::Display for Install {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = self
+            .version
+            .as_ref()
+            .map_or_else(|| "unknown".to_string(), |v| v.to_string());
+        write!(
+            f,
+            "{:<9} {} ({})",
+            version,
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+fn data_home() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home().map(|home| home.join(".local/share")))
+}
+
+/// Data directories of community launchers, searched directly for the game
+fn launcher_dirs() -> Vec<(String, PathBuf)> {
+    let mut dirs = vec![];
+
+    if let Some(data) = data_home() {
+        dirs.push((
+            "honkers-railway-launcher".to_string(),
+            data.join("honkers-railway-launcher"),
+        ));
+    }
+    if let Some(home) = home() {
+        dirs.push((
+            "honkers-railway-launcher (Flatpak)".to_string(),
+            home.join(
+                ".var/app/moe.launcher.the-honkers-railway-launcher/data/honkers-railway-launcher",
+            ),
+        ));
+        dirs.push(("Lutris".to_string(), home.join("Games")));
+    }
+
+    if cfg!(target_os = "windows") {
+        for base in ["C:\\Program Files", "C:\\Program Files (x86)"] {
+            dirs.push(("HoYoPlay".to_string(), Path::new(base).join("HoYoPlay")));
+            dirs.push((
+                "Star Rail launcher".to_string(),
+                Path::new(base).join("Star Rail"),
+            ));
+        }
+    }
+
+    dirs
+}
+
+/// Wine and Proton prefixes, searched below their `drive_c`
+fn wine_prefixes() -> Vec<(String, PathBuf)> {
+    let mut prefixes = vec![];
+
+    if let Some(prefix) = env::var_os("WINEPREFIX") {
+        prefixes.push(("WINEPREFIX".to_string(), PathBuf::from(prefix)));
+    }
+    if let Some(home) = home() {
+        prefixes.push(("Wine".to_string(), home.join(".wine")));
+    }
+
+    let steam_roots = [
+        data_home().map(|data| data.join("Steam")),
+        home().map(|home| home.join(".steam/steam")),
+        home().map(|home| home.join(".var/app/com.valvesoftware.Steam/data/Steam")),
+    ];
+    for steam in steam_roots.into_iter().flatten() {
+        let Ok(entries) = steam.join("steamapps/compatdata").read_dir() else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            prefixes.push((
+                format!("Proton {}", entry.file_name().to_string_lossy()),
+                entry.path().join("pfx"),
+            ));
+        }
+    }
+
+    // Lutris keeps one prefix per game below ~/Games
+    if let Some(Ok(entries)) = home().map(|home| home.join("Games").read_dir()) {
+        for entry in entries.filter_map(Result::ok) {
+            prefixes.push((
+                format!("Lutris {}", entry.file_name().to_string_lossy()),
+                entry.path(),
+            ));
+        }
+    }
+
+    prefixes
+}
+
+/// Collect every directory containing the game executable up to `depth` levels below `dir`
+fn search(dir: &Path, depth: usize, found: &mut Vec<PathBuf>) {
+    if dir.join(GAME_EXECUTABLE).is_file() {
+        found.push(dir.to_path_buf());
+        return;
+    }
+    if depth == 0 {
+        return;
+    }
+
+    let Ok(entries) = dir.read_dir() else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        // Don't wander into prefixes through dosdevices or other links
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            search(&entry.path(), depth - 1, found);
+        }
+    }
+}
+
+/// Find game installs in launcher data directories and Wine prefixes
+pub fn discover() -> Vec<Install> {
+    let mut roots = launcher_dirs();
+    for (source, prefix) in wine_prefixes() {
+        for dir in PREFIX_SEARCH_DIRS {
+            roots.push((source.clone(), prefix.join("drive_c").join(dir)));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut installs = vec![];
+
+    for (source, root) in roots {
+        let mut found = vec![];
+        search(&root, MAX_DEPTH, &mut found);
+
+        for path in found {
+            let key = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if !seen.insert(key) {
+                continue;
+            }
+
+            let version = BinaryVersion::parse(&path.join(paths::native(BINARY_VERSION_PATH))).ok();
+            installs.push(Install {
+                path,
+                source: source.clone(),
+                version,
+            });
+        }
+    }
+
+    installs
+}
+
+/// Numbered listing as printed by `hdiff-apply installs`
+pub fn format_list(installs: &[Install]) -> String {
+    installs
+        .iter()
+        .enumerate()
+        .map(|(i, install)| format!("[{i}] {install}\n"))
+        .collect()
+}
+
+/// Pick an install by its index in the listing or by a case-insensitive part of
+/// its path or source
+pub fn select<'a>(installs: &'a [Install], selector: &str) -> Result<&'a Install, DiscoveryError> {
+    if let Some(install) = selector.parse::<usize>().ok().and_then(|i| installs.get(i)) {
+        return Ok(install);
+    }
+
+    let needle = selector.to_lowercase();
+    let matches: Vec<_> = installs
+        .iter()
+        .filter(|install| install.to_string().to_lowercase().contains(&needle))
+        .collect();
+
+    match matches.as_slice() {
+        [] => Err(DiscoveryError::NoMatch(selector.to_string())),
+        [install] => Ok(install),
+        _ => Err(DiscoveryError::Ambiguous(
+            selector.to_string(),
+            matches.iter().map(|m| format!("    {m}\n")).collect(),
+        )),
+    }
+}
+
+/// Use the only discovered install, or fail listing them when there are several
+pub fn single(installs: &[Install]) -> Result<Option<&Install>, DiscoveryError> {
+    match installs {
+        [] => Ok(None),
+        [install] => Ok(Some(install)),
+        _ => Err(DiscoveryError::Several(format_list(installs))),
+    }
+}
@@ -1,24 +1,57 @@
 use thiserror::Error;
 
-use crate::{deletefiles, hdiffmap, seven_util};
+use crate::{
+    backup, checksum, config, deletefiles, discovery, game_process, hdiffmap, inspect,
+    out_of_place, package, paths, reverse, seven_util, squash, tools, workspace,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
+    #[error[transparent]]
+    BackupError(#[from] backup::BackupError),
+    #[error[transparent]]
+    ChecksumError(#[from] checksum::ChecksumError),
+    #[error[transparent]]
+    ConfigError(#[from] config::ConfigError),
+    #[error[transparent]]
+    DiscoveryError(#[from] discovery::DiscoveryError),
     #[error[transparent]]
     DeleteFileError(#[from] deletefiles::DeleteFileError),
     #[error[transparent]]
+    ProcessError(#[from] game_process::ProcessError),
+    #[error[transparent]]
     PatchError(#[from] hdiffmap::PatchError),
     #[error[transparent]]
+    InspectError(#[from] inspect::InspectError),
+    #[error[transparent]]
+    LockError(#[from] workspace::LockError),
+    #[error[transparent]]
+    OutputError(#[from] out_of_place::OutputError),
+    #[error[transparent]]
+    PackageError(#[from] package::PackageError),
+    #[error[transparent]]
+    PathViolation(#[from] paths::PathError),
+    #[error[transparent]]
+    ReverseError(#[from] reverse::ReverseError),
+    #[error[transparent]]
     SevenError(#[from] seven_util::SevenError),
+    #[error[transparent]]
+    SquashError(#[from] squash::SquashError),
+    #[error[transparent]]
+    ToolError(#[from] tools::ToolError),
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    #[error("StarRail.exe not found in the current directory: {0}\nTip: Pass the game path as the first argument if it's not in the current directory or move this .exe")]
+    #[error("StarRail.exe not found in the current directory: {0}\nTip: Pass the game path as the first argument, pick a discovered install with --install or move this .exe")]
     PathNotFound(String),
     #[error("Hdiff archive was not found in the client directory!")]
     ArchiveNotFound(),
     #[error("Failed to parse BinaryVersion.bytes: could not extract version string!")]
     VersionParse(),
+    #[error("{0} of {1} source files are missing or don't match the manifest, nothing was modified\nTip: Pass --on-mismatch skip to patch the remaining files anyway")]
+    SourceMismatch(usize, usize),
+    #[error("{0} of {1} files failed to patch while applying {2}, not applying any newer package")]
+    PatchFailed(usize, usize, String),
     #[error("Incompatible hdiff version: cannot update client from {0} to {1} if you believe this is a mistake use the --skip-version-check flag to override")]
     InvalidHdiffVersion(String, String),
 }
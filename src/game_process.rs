@@ -0,0 +1,366 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{config::RunningGamePolicy, paths::GameDir};
+
+const WAIT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Error)]
+pub enum ProcessError {
+    #[error("{0} must be closed before updating, or pass --force to update anyway")]
+    GameRunning(String),
+}
+
+/// Why a process is considered to be using the game
+#[derive(Debug)]
+pub enum Usage {
+    /// Runs an executable inside the game directory
+    Executable(PathBuf),
+    /// Runs with its working directory inside the game directory
+    WorkingDir(PathBuf),
+    /// Has a file inside the game directory open
+    OpenFile(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct GameProcess {
+    pub pid: u32,
+    pub name: String,
+    pub usage: Usage,
+}
+
+impl fmt::Display for GameProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.usage {
+            Usage::Executable(path) => {
+                write!(
+                    f,
+                    "{} (pid {}) running {}",
+                    self.name,
+                    self.pid,
+                    path.display()
+                )
+            }
+            Usage::WorkingDir(path) => {
+                write!(f, "{} (pid {}) in {}", self.name, self.pid, path.display())
+            }
+            Usage::OpenFile(path) => {
+                write!(
+                    f,
+                    "{} (pid {}) with {} open",
+                    self.name,
+                    self.pid,
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+/// `\\?\C:\dir` as `C:\dir` and `\\?\UNC\server\share` as `\\server\share`. Canonical paths
+/// on Windows are verbatim, the paths reported for processes aren't
+fn without_verbatim_prefix(path: &str) -> Cow<'_, str> {
+    if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        Cow::Owned(format!(r"\\{unc}"))
+    } else {
+        Cow::Borrowed(path.strip_prefix(r"\\?\").unwrap_or(path))
+    }
+}
+
+/// Form of `path` that compares equal however the platform spells the same location.
+/// Windows paths are case-insensitive
+fn comparable(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        let path = path.to_string_lossy();
+        PathBuf::from(
+            without_verbatim_prefix(&path)
+                .to_lowercase()
+                .replace('/', "\\"),
+        )
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Only what lies inside the directory being written counts, a copy of the game running
+/// from another install doesn't get in the way
+fn is_inside(game_dir: &Path, path: &Path) -> bool {
+    comparable(path).starts_with(comparable(game_dir))
+}
+
+/// Find processes that run from the install, work inside it or keep its files open
+pub fn find(game_dir: &GameDir) -> Vec<GameProcess> {
+    let root = game_dir
+        .path()
+        .canonicalize()
+        .unwrap_or_else(|_| game_dir.path().to_path_buf());
+
+    let mut processes = platform::processes(&root);
+    processes.retain(|p| p.pid != std::process::id());
+    processes
+}
+
+/// Apply `policy` to the processes using the game directory. Returns once none are left,
+/// when `force` is set or with an error when the policy is to abort
+pub fn ensure_not_running(
+    game_dir: &GameDir,
+    policy: RunningGamePolicy,
+    force: bool,
+) -> Result<(), ProcessError> {
+    let mut waiting = false;
+
+    loop {
+        let processes = find(game_dir);
+        if processes.is_empty() {
+            if waiting {
+                tracing::info!("The game was closed, continuing");
+            }
+            return Ok(());
+        }
+
+        if force {
+            for process in &processes {
+                tracing::warn!("Updating anyway although {} (--force)", process);
+            }
+            return Ok(());
+        }
+
+        match policy {
+            RunningGamePolicy::Abort => {
+                for process in &processes {
+                    tracing::error!("Found {}", process);
+                }
+                let names: Vec<&str> = processes.iter().map(|p| p.name.as_str()).collect();
+                return Err(ProcessError::GameRunning(names.join(", ")));
+            }
+            RunningGamePolicy::Wait => {
+                if !waiting {
+                    for process in &processes {
+                        tracing::warn!("Found {}", process);
+                    }
+                    tracing::info!("Waiting for the game and launcher to be closed...");
+                    waiting = true;
+                }
+                thread::sleep(WAIT_INTERVAL);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::{is_inside, GameProcess, Usage};
+
+    /// Value of `name` in a process' environment
+    fn environment_variable(environ: &[u8], name: &str) -> Option<PathBuf> {
+        environ
+            .split(|b| *b == 0)
+            .filter_map(|var| var.strip_prefix(name.as_bytes())?.strip_prefix(b"="))
+            .find(|value| !value.is_empty())
+            .map(|value| PathBuf::from(String::from_utf8_lossy(value).into_owned()))
+    }
+
+    /// Host path of a Wine process' executable. Drive letters are mapped through the
+    /// `dosdevices` links of the process' prefix, `$WINEPREFIX` or `~/.wine`, and `Z:`
+    /// falls back to `/` where Wine points it by default
+    pub(super) fn wine_executable(pid_dir: &Path, cmdline: &[u8]) -> Option<PathBuf> {
+        let argv0 = cmdline.split(|b| *b == 0).next()?;
+        let argv0 = String::from_utf8_lossy(argv0);
+        if !argv0.to_ascii_lowercase().ends_with(".exe") {
+            return None;
+        }
+
+        let mut chars = argv0.chars();
+        let drive = chars.next()?.to_ascii_lowercase();
+        if !drive.is_ascii_alphabetic() || chars.next()? != ':' {
+            return None;
+        }
+        let relative = chars.as_str().replace('\\', "/");
+        let relative = relative.trim_start_matches('/');
+
+        let environ = fs::read(pid_dir.join("environ")).unwrap_or_default();
+        let prefix = environment_variable(&environ, "WINEPREFIX")
+            .or_else(|| environment_variable(&environ, "HOME").map(|home| home.join(".wine")));
+        let mapped = prefix
+            .map(|prefix| prefix.join("dosdevices").join(format!("{drive}:")))
+            .filter(|device| device.exists())
+            .map(|device| device.join(relative))
+            .or_else(|| (drive == 'z').then(|| Path::new("/").join(relative)))?;
+
+        // The game directory is canonical, dosdevices entries are links into the prefix
+        Some(mapped.canonicalize().unwrap_or(mapped))
+    }
+
+    fn open_file(pid_dir: &Path, game_dir: &Path) -> Option<PathBuf> {
+        fs::read_dir(pid_dir.join("fd"))
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .find(|target| is_inside(game_dir, target) && target.is_file())
+    }
+
+    /// Scan `/proc`. Wine runs Windows programs as regular processes, so their open
+    /// files show up there like any other. Processes of other users can't be inspected
+    pub fn processes(game_dir: &Path) -> Vec<GameProcess> {
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return vec![];
+        };
+
+        let mut processes = vec![];
+        for entry in entries.filter_map(Result::ok) {
+            let Some(pid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            let pid_dir = entry.path();
+
+            let executable = fs::read_link(pid_dir.join("exe"))
+                .ok()
+                .filter(|exe| is_inside(game_dir, exe))
+                .or_else(|| {
+                    fs::read(pid_dir.join("cmdline"))
+                        .ok()
+                        .and_then(|cmdline| wine_executable(&pid_dir, &cmdline))
+                        .filter(|exe| is_inside(game_dir, exe))
+                });
+
+            let cwd = || {
+                fs::read_link(pid_dir.join("cwd"))
+                    .ok()
+                    .filter(|cwd| is_inside(game_dir, cwd))
+            };
+
+            let usage = if let Some(exe) = executable {
+                Usage::Executable(exe)
+            } else if let Some(cwd) = cwd() {
+                Usage::WorkingDir(cwd)
+            } else if let Some(file) = open_file(&pid_dir, game_dir) {
+                Usage::OpenFile(file)
+            } else {
+                continue;
+            };
+
+            let name = fs::read_to_string(pid_dir.join("comm"))
+                .map(|comm| comm.trim().to_string())
+                .unwrap_or_else(|_| pid.to_string());
+
+            processes.push(GameProcess { pid, name, usage });
+        }
+
+        processes
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::{path::Path, process::Command};
+
+    use super::{is_inside, GameProcess, Usage};
+
+    const LIST_PROCESSES: &str = "Get-CimInstance Win32_Process | ForEach-Object { \"$($_.ProcessId)`t$($_.Name)`t$($_.ExecutablePath)\" }";
+
+    /// Ask PowerShell for the executable path of every process. Working directories and
+    /// open files of other processes can't be read without elevated handle queries, so
+    /// only executables inside the game directory are found here. A launcher installed
+    /// elsewhere that holds game files open isn't detected, the update then fails on
+    /// the locked files instead
+    pub fn processes(game_dir: &Path) -> Vec<GameProcess> {
+        let output = match Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", LIST_PROCESSES])
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) => {
+                tracing::warn!("Failed to list running processes: {}", e);
+                return vec![];
+            }
+        };
+
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.trim_end().splitn(3, '\t');
+                let pid = fields.next()?.parse().ok()?;
+                let name = fields.next()?.to_string();
+                let exe = Path::new(fields.next().filter(|p| !p.is_empty()).unwrap_or(&name));
+
+                is_inside(game_dir, exe).then(|| GameProcess {
+                    pid,
+                    name: name.clone(),
+                    usage: Usage::Executable(exe.to_path_buf()),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbatim_prefix_is_stripped() {
+        assert_eq!(without_verbatim_prefix(r"\\?\C:\Games\SR"), r"C:\Games\SR");
+        assert_eq!(
+            without_verbatim_prefix(r"\\?\UNC\nas\games\SR"),
+            r"\\nas\games\SR"
+        );
+        assert_eq!(without_verbatim_prefix(r"C:\Games\SR"), r"C:\Games\SR");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wine_drives_are_mapped_through_the_prefix() {
+        use std::{fs, os::unix::fs::symlink};
+
+        let root = std::env::temp_dir().join(format!("hdiff-apply-wine-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let game = root.join("pfx/drive_c/Games/SR");
+        fs::create_dir_all(&game).unwrap();
+        fs::write(game.join("StarRail.exe"), "").unwrap();
+        fs::create_dir_all(root.join("pfx/dosdevices")).unwrap();
+        symlink("../drive_c", root.join("pfx/dosdevices/c:")).unwrap();
+
+        let pid_dir = root.join("proc");
+        fs::create_dir_all(&pid_dir).unwrap();
+        let environ = format!("HOME=/nowhere\0WINEPREFIX={}\0", root.join("pfx").display());
+        fs::write(pid_dir.join("environ"), environ).unwrap();
+
+        let game = game.canonicalize().unwrap();
+        let exe = platform::wine_executable(&pid_dir, b"C:\\Games\\SR\\StarRail.exe\0-arg\0");
+        assert_eq!(exe, Some(game.join("StarRail.exe")));
+        assert!(is_inside(&game, &exe.unwrap()));
+
+        // Z: is `/` even without a prefix, other drives can't be placed then
+        fs::write(pid_dir.join("environ"), "").unwrap();
+        assert_eq!(
+            platform::wine_executable(&pid_dir, b"Z:\\opt\\game\\launcher.exe\0"),
+            Some(PathBuf::from("/opt/game/launcher.exe"))
+        );
+        assert_eq!(
+            platform::wine_executable(&pid_dir, b"C:\\Games\\SR\\StarRail.exe\0"),
+            None
+        );
+        assert_eq!(
+            platform::wine_executable(&pid_dir, b"/usr/bin/wine\0"),
+            None
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
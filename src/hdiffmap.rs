@@ -1,59 +1,320 @@
-use rayon::prelude::*;
 use serde::Deserialize;
 use serde_json::Value;
 use std::{
-    path::Path,
-    process::Command,
-    sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc, Mutex,
-    },
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+    time::Duration,
 };
 use thiserror::Error;
 
-pub struct HDiffMap<'a, 'b> {
-    game_path: &'a Path,
-    hpatchz_path: &'b Path,
-    count: Arc<Mutex<u32>>,
+use crate::{
+    backup::{Backup, BackupError},
+    checksum::{self, Algorithm},
+    out_of_place,
+    paths::{GameDir, PathError},
+    seven_util::{SevenError, SevenUtil},
+    tools::{Tool, ToolError},
+    utils,
+};
+
+pub struct HDiffMap<'a> {
+    game_dir: &'a GameDir,
+    hpatchz: &'a Tool,
+    hdiff_archive: &'a Path,
+    options: PatchOptions<'a>,
+    /// Held while a source is checked and restored so entries sharing it don't race
+    restore_lock: Mutex<()>,
+}
+
+pub struct PatchOptions<'a> {
+    /// Bytes of patch sources processed at once, 0 disables the limit
+    pub memory_budget: u64,
+    /// Extra attempts for an entry that failed to patch
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further attempt
+    pub retry_delay: Duration,
+    /// Where damaged or missing sources are restored from before retrying
+    pub backup: Option<&'a Backup>,
+    /// Resolved sources that failed verification, their entries are left out
+    pub skip: &'a HashSet<PathBuf>,
 }
 
 #[derive(Debug, Error)]
 pub enum PatchError {
     #[error("hdiffmap.json structure changed!")]
     Json(),
+    #[error("{0} is malformed: {1}")]
+    Malformed(String, #[source] serde_json::Error),
+    #[error("{0} doesn't match the manifest and neither does its backup")]
+    StaleBackup(String),
     #[error("{0} doesn't exist, skipping")]
     NotFound(String),
+    #[error("Failed to execute hpatchz: {0}")]
+    Spawn(#[source] std::io::Error),
+    #[error("hpatchz failed with {status}: {stderr}")]
+    Hpatchz { status: String, stderr: String },
+    #[error(transparent)]
+    Path(#[from] PathError),
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+    #[error("Failed to re-extract patch file: {0}")]
+    Reextract(#[from] SevenError),
+    #[error(transparent)]
+    Tool(#[from] ToolError),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+/// Outcome of a single hdiffmap.json entry
+#[derive(Debug)]
+pub struct EntryResult {
+    pub target_file_name: String,
+    pub result: Result<(), PatchError>,
+}
+
+#[derive(Debug, Default)]
+pub struct PatchReport {
+    pub entries: Vec<EntryResult>,
+    /// Entries left out because their source failed verification
+    pub skipped: usize,
+}
+
+impl PatchReport {
+    pub fn patched(&self) -> usize {
+        self.entries.iter().filter(|e| e.result.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &EntryResult> {
+        self.entries.iter().filter(|e| e.result.is_err())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.entries.iter().all(|e| e.result.is_ok())
+    }
+}
+
 #[derive(Deserialize)]
-struct DiffMap {
-    source_file_name: String,
-    target_file_name: String,
-    patch_file_name: String,
+pub struct DiffMap {
+    pub source_file_name: String,
+    #[serde(default)]
+    pub source_file_size: u64,
+    #[serde(default)]
+    pub source_file_md5: String,
+    pub target_file_name: String,
+    #[serde(default)]
+    pub target_file_size: u64,
+    pub patch_file_name: String,
+    #[serde(default)]
+    pub patch_file_size: u64,
+
+    // Filled in after the names were checked against the game directory
+    #[serde(skip)]
+    pub source_path: PathBuf,
+    #[serde(skip)]
+    pub target_path: PathBuf,
+    #[serde(skip)]
+    pub patch_path: PathBuf,
+}
+
+impl DiffMap {
+    /// Source, target and patch file name as written in the manifest
+    pub fn file_names(&self) -> [&str; 3] {
+        [
+            &self.source_file_name,
+            &self.target_file_name,
+            &self.patch_file_name,
+        ]
+    }
 }
 
-impl<'a, 'b> HDiffMap<'a, 'b> {
-    pub fn new(game_path: &'a Path, hpatchz_path: &'b Path) -> Self {
+/// Read the `diff_map` entries of an hdiffmap.json, resolving every path
+/// against the game directory before any of them is used
+pub fn load_diff_map(game_dir: &GameDir, hdiffmap_path: &Path) -> Result<Vec<DiffMap>, PatchError> {
+    if !hdiffmap_path.exists() {
+        return Err(PatchError::NotFound(format!("{}", hdiffmap_path.display())));
+    }
+
+    let malformed = |e| PatchError::Malformed(hdiffmap_path.display().to_string(), e);
+    let data = std::fs::read_to_string(hdiffmap_path)?;
+    let deserialized: Value = serde_json::from_str(&data).map_err(malformed)?;
+
+    let diff_map = deserialized.get("diff_map").ok_or(PatchError::Json())?;
+    let mut diff_map: Vec<DiffMap> = serde_json::from_value(diff_map.clone()).map_err(malformed)?;
+
+    for entry in &mut diff_map {
+        entry.source_path = game_dir.resolve(&entry.source_file_name)?;
+        entry.target_path = game_dir.resolve(&entry.target_file_name)?;
+        entry.patch_path = game_dir.resolve(&entry.patch_file_name)?;
+    }
+
+    Ok(diff_map)
+}
+
+/// Order in which the entries of a manifest are patched
+#[derive(Debug, PartialEq, Eq)]
+struct Schedule {
+    /// Indices of the entries, each wave only starts once the previous one is done
+    waves: Vec<Vec<usize>>,
+    /// Entries reading a copy of their source taken before the first wave, which breaks
+    /// cycles such as two files swapping places
+    copied: BTreeSet<usize>,
+}
+
+/// Split the entries into waves that have to run one after another. Every entry
+/// reads the old version of its source, so an entry overwriting a file has to wait
+/// until every other entry reading that file is done. When entries wait on each other
+/// in a cycle, one of them reads a copy instead and nobody has to wait for it
+fn schedule(diff_map: &[DiffMap]) -> Schedule {
+    let mut readers: HashMap<&Path, Vec<usize>> = HashMap::new();
+    for (i, entry) in diff_map.iter().enumerate() {
+        readers.entry(&entry.source_path).or_default().push(i);
+    }
+
+    #[derive(Clone, Copy)]
+    enum State {
+        Unvisited,
+        Visiting,
+        Done(usize),
+    }
+
+    /// Wave of entry `i`, or `Err` with an entry that is part of a cycle
+    fn level_of(
+        i: usize,
+        dependencies: &[Vec<usize>],
+        states: &mut [State],
+    ) -> Result<usize, usize> {
+        match states[i] {
+            State::Done(level) => return Ok(level),
+            State::Visiting => return Err(i),
+            State::Unvisited => {}
+        }
+
+        states[i] = State::Visiting;
+        let mut level = 0;
+        for &dependency in &dependencies[i] {
+            level = level.max(level_of(dependency, dependencies, states)? + 1);
+        }
+        states[i] = State::Done(level);
+
+        Ok(level)
+    }
+
+    let mut copied = BTreeSet::new();
+    'attempt: loop {
+        // Entries that have to finish before entry `i` may write its target
+        let dependencies: Vec<Vec<usize>> = diff_map
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                readers
+                    .get(entry.target_path.as_path())
+                    .map(|r| {
+                        r.iter()
+                            .copied()
+                            .filter(|&r| r != i && !copied.contains(&r))
+                            .collect()
+                    })
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        let mut states = vec![State::Unvisited; diff_map.len()];
+        let mut waves: Vec<Vec<usize>> = vec![];
+        for i in 0..diff_map.len() {
+            let level = match level_of(i, &dependencies, &mut states) {
+                Ok(level) => level,
+                Err(in_cycle) => {
+                    copied.insert(in_cycle);
+                    continue 'attempt;
+                }
+            };
+            if waves.len() <= level {
+                waves.resize(level + 1, vec![]);
+            }
+            waves[level].push(i);
+        }
+
+        return Schedule { waves, copied };
+    }
+}
+
+/// Hidden copy of a source next to it, read by entries that can't wait for the original
+fn source_copy_path(source: &Path) -> PathBuf {
+    let file_name = source
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    source.with_file_name(format!(".{file_name}.hdiff-apply.orig"))
+}
+
+/// Tracks how many entries still need each source so it's only removed after the last one
+struct SourceUsage {
+    remaining: usize,
+    failed: bool,
+    /// Also written by some entry, so it must never be removed
+    keep: bool,
+}
+
+/// Limits how many bytes of patch sources are being processed at the same time
+struct MemoryBudget {
+    available: Mutex<u64>,
+    released: Condvar,
+    total: u64,
+}
+
+impl MemoryBudget {
+    fn new(total: u64) -> Self {
         Self {
-            game_path,
-            hpatchz_path,
-            count: Arc::new(Mutex::new(0)),
+            available: Mutex::new(total),
+            released: Condvar::new(),
+            total,
         }
     }
 
-    fn load_diff_map(&self, hdiffmap_path: &Path) -> Result<Vec<DiffMap>, PatchError> {
-        if !hdiffmap_path.exists() {
-            return Err(PatchError::NotFound(format!("{}", hdiffmap_path.display())));
+    /// Block until `size` bytes are free. Entries larger than the whole budget
+    /// wait for everything else to finish and then run alone
+    fn acquire(&self, size: u64) -> u64 {
+        let size = size.min(self.total);
+        let mut available = self.available.lock().unwrap();
+        while *available < size {
+            available = self.released.wait(available).unwrap();
         }
+        *available -= size;
+        size
+    }
 
-        let data = std::fs::read_to_string(&hdiffmap_path)?;
-        let deserialized: Value = serde_json::from_str(&data).unwrap();
+    fn release(&self, size: u64) {
+        *self.available.lock().unwrap() += size;
+        self.released.notify_all();
+    }
+}
 
-        let diff_map = deserialized.get("diff_map").ok_or(PatchError::Json())?;
+impl<'a> HDiffMap<'a> {
+    pub fn new(
+        game_dir: &'a GameDir,
+        hpatchz: &'a Tool,
+        hdiff_archive: &'a Path,
+        options: PatchOptions<'a>,
+    ) -> Self {
+        Self {
+            game_dir,
+            hpatchz,
+            hdiff_archive,
+            options,
+            restore_lock: Mutex::new(()),
+        }
+    }
 
-        Ok(serde_json::from_value(diff_map.clone()).unwrap())
+    /// Every file the manifest reads or writes, relative to the game directory
+    pub fn affected_files(&self, hdiffmap_path: &Path) -> Result<Vec<String>, PatchError> {
+        Ok(load_diff_map(self.game_dir, hdiffmap_path)?
+            .into_iter()
+            .flat_map(|entry| [entry.source_file_name, entry.target_file_name])
+            .collect())
     }
 
     fn remove_file<P: AsRef<Path>>(&self, path: P) {
@@ -63,55 +324,365 @@ impl<'a, 'b> HDiffMap<'a, 'b> {
         }
     }
 
-    pub fn patch(&mut self, hdiffmap_path: &Path) -> Result<(), PatchError> {
-        let path = self.game_path;
-        let hpatchz_path = self.hpatchz_path;
+    /// Apply every entry of the manifest and report how each of them went.
+    /// Only a missing or malformed manifest is returned as an error
+    pub fn patch(&self, hdiffmap_path: &Path) -> Result<PatchReport, PatchError> {
+        let (mut diff_map, skipped): (Vec<DiffMap>, Vec<DiffMap>) =
+            load_diff_map(self.game_dir, hdiffmap_path)?
+                .into_iter()
+                .partition(|entry| !self.options.skip.contains(&entry.source_path));
+        for entry in &skipped {
+            tracing::warn!(
+                "Not patching {}, its source didn't pass verification",
+                entry.target_file_name
+            );
+            self.remove_file(&entry.patch_path);
+        }
+
+        let Schedule { waves, copied } = schedule(&diff_map);
+        for &i in &copied {
+            let entry = &mut diff_map[i];
+            let copy = source_copy_path(&entry.source_path);
+            // A copy left by an interrupted run still holds the original content
+            if !copy.exists() {
+                let result = out_of_place::reflink(&entry.source_path, &copy)
+                    .or_else(|_| std::fs::copy(&entry.source_path, &copy).map(|_| ()));
+                match result {
+                    Ok(()) => {}
+                    // Left to the entry, which repairs or reports its missing source
+                    Err(_) if !entry.source_path.exists() => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            tracing::debug!(
+                "{} reads a copy of {}, another entry overwrites it",
+                entry.target_file_name,
+                entry.source_file_name
+            );
+            entry.source_path = copy;
+        }
+
+        let results = Mutex::new(Vec::with_capacity(diff_map.len()));
 
-        let diff_map = self.load_diff_map(hdiffmap_path)?;
-        let counter = AtomicU32::new(0);
+        let mut usage: HashMap<&Path, SourceUsage> = HashMap::new();
+        for entry in &diff_map {
+            usage
+                .entry(&entry.source_path)
+                .or_insert(SourceUsage {
+                    remaining: 0,
+                    failed: false,
+                    keep: false,
+                })
+                .remaining += 1;
+        }
+        for entry in &diff_map {
+            if let Some(usage) = usage.get_mut(entry.target_path.as_path()) {
+                usage.keep = true;
+            }
+        }
+        let usage = Mutex::new(usage);
 
-        diff_map.into_par_iter().for_each(|entry| {
-            let source_file_name = path.join(&entry.source_file_name);
-            let patch_file_name = path.join(&entry.patch_file_name);
-            let target_file_name = path.join(&entry.target_file_name);
+        let budget = match self.options.memory_budget {
+            0 => None,
+            total => Some(MemoryBudget::new(total)),
+        };
 
-            let output = Command::new(&hpatchz_path)
-                .arg(&source_file_name)
-                .arg(&patch_file_name)
-                .arg(&target_file_name)
-                .arg("-f")
-                .output();
+        if waves.len() > 1 {
+            tracing::debug!("Patching in {} dependent waves", waves.len());
+        }
 
-            match output {
-                Ok(out) => {
-                    if out.status.success() {
-                        counter.fetch_add(1, Ordering::Relaxed);
+        for mut wave in waves {
+            // Start the biggest files first so they don't end up running alone at the end
+            wave.sort_by_key(|&i| std::cmp::Reverse(diff_map[i].source_file_size));
 
-                        if !out.stdout.is_empty() {
-                            tracing::info!("{}", String::from_utf8_lossy(&out.stdout).trim());
-                        }
+            rayon::scope_fifo(|scope| {
+                for entry in wave.iter().map(|&i| &diff_map[i]) {
+                    let results = &results;
+                    let usage = &usage;
+                    let budget = budget.as_ref();
 
-                        self.remove_file(patch_file_name);
-                        if source_file_name != target_file_name {
-                            self.remove_file(source_file_name);
+                    scope.spawn_fifo(move |_| {
+                        let reserved = budget.map(|b| b.acquire(entry.source_file_size));
+                        let result = self.patch_entry(entry);
+                        if let (Some(budget), Some(reserved)) = (budget, reserved) {
+                            budget.release(reserved);
                         }
-                    } else {
-                        if !out.stderr.is_empty() {
-                            tracing::error!("{}", String::from_utf8_lossy(&out.stderr).trim());
-                        }
-                    }
+
+                        self.release_source(usage, entry, result.is_ok());
+
+                        results.lock().unwrap().push(EntryResult {
+                            target_file_name: entry.target_file_name.clone(),
+                            result,
+                        });
+                    });
                 }
-                Err(e) => {
-                    tracing::error!("Failed to execute patch command: {}", e);
+            });
+        }
+
+        Ok(PatchReport {
+            entries: results.into_inner().unwrap(),
+            skipped: skipped.len(),
+        })
+    }
+
+    /// Remove the source once its last consumer is done, unless any of them failed
+    /// or another entry writes to it
+    fn release_source(
+        &self,
+        usage: &Mutex<HashMap<&Path, SourceUsage>>,
+        entry: &DiffMap,
+        succeeded: bool,
+    ) {
+        let remove = {
+            let mut usage = usage.lock().unwrap();
+            let Some(source) = usage.get_mut(entry.source_path.as_path()) else {
+                return;
+            };
+
+            source.remaining -= 1;
+            source.failed |= !succeeded;
+            source.remaining == 0 && !source.failed && !source.keep
+        };
+
+        if remove {
+            self.remove_file(&entry.source_path);
+        }
+    }
+
+    /// Patch one entry, retrying with backoff and repairing its inputs between attempts
+    fn patch_entry(&self, entry: &DiffMap) -> Result<(), PatchError> {
+        let mut delay = self.options.retry_delay;
+        let mut attempt = 0;
+
+        loop {
+            let result = self
+                .prepare_inputs(entry)
+                .and_then(|_| self.run_hpatchz(entry));
+
+            match result {
+                Ok(()) => break,
+                // Retrying can't make the backup match
+                Err(e @ PatchError::StaleBackup(_)) => return Err(e),
+                Err(e) if attempt < self.options.retries => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Failed to patch {}: {}, retrying in {:.2?} ({}/{})",
+                        entry.target_file_name,
+                        e,
+                        delay,
+                        attempt,
+                        self.options.retries
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
                 }
+                Err(e) => return Err(e),
             }
-        });
+        }
+
+        tracing::info!("Patched {}", entry.target_file_name);
+
+        self.remove_file(&entry.patch_path);
+
+        Ok(())
+    }
+
+    /// Re-extract the patch file if it's missing or damaged and restore the source
+    /// from the backup if it doesn't look like the manifest expects it to
+    fn prepare_inputs(&self, entry: &DiffMap) -> Result<(), PatchError> {
+        if !has_expected_size(&entry.patch_path, entry.patch_file_size) {
+            tracing::info!("Re-extracting {}", entry.patch_file_name);
+            SevenUtil::inst().extract_paths_to(
+                self.hdiff_archive,
+                &[&entry.patch_file_name],
+                self.game_dir.path(),
+            )?;
+        }
+
+        if has_expected_size(&entry.source_path, entry.source_file_size) {
+            return Ok(());
+        }
+        let Some(backup) = self
+            .options
+            .backup
+            .filter(|b| b.contains(&entry.source_file_name))
+        else {
+            return Ok(());
+        };
+
+        let _guard = self.restore_lock.lock().unwrap();
+        // Another entry reading the same source may have restored it meanwhile
+        if has_expected_size(&entry.source_path, entry.source_file_size) {
+            return Ok(());
+        }
+
+        // The backup holds the state before the chain, which is the wrong version for
+        // sources written by an earlier package
+        let saved = backup.backup_file(&entry.source_file_name)?;
+        if !matches_manifest(&saved, entry) {
+            return Err(PatchError::StaleBackup(entry.source_file_name.clone()));
+        }
+        // A copied source is restored into the copy, the original may already be patched
+        backup.restore_to(&entry.source_file_name, &entry.source_path)?;
 
-        *self.count.lock().unwrap() = counter.load(Ordering::Relaxed);
         Ok(())
     }
 
-    pub fn count(&self) -> u32 {
-        *self.count.lock().unwrap()
+    /// Let hpatchz write a temporary file next to the target and only replace the target
+    /// once that file is complete and synced, so the target is always either fully old
+    /// or fully new even if the process dies midway
+    fn run_hpatchz(&self, entry: &DiffMap) -> Result<(), PatchError> {
+        let temp_target = temp_target_path(&entry.target_path);
+        if let Some(parent) = temp_target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let output = self
+            .hpatchz
+            .command()?
+            .arg(&entry.source_path)
+            .arg(&entry.patch_path)
+            .arg(&temp_target)
+            .arg("-f")
+            .output()
+            .map_err(PatchError::Spawn);
+
+        let result = output.and_then(|output| {
+            utils::log_tool_output("hpatchz", &entry.target_path, &output);
+
+            if !output.status.success() {
+                return Err(PatchError::Hpatchz {
+                    status: output.status.to_string(),
+                    stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                });
+            }
+
+            // Windows can only flush handles opened for writing
+            OpenOptions::new()
+                .write(true)
+                .open(&temp_target)?
+                .sync_all()?;
+            std::fs::rename(&temp_target, &entry.target_path)?;
+            sync_parent_dir(&entry.target_path);
+
+            Ok(())
+        });
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_target);
+        }
+
+        result
+    }
+}
+
+/// Hidden file in the target's directory so the final rename never crosses filesystems
+pub fn temp_target_path(target: &Path) -> PathBuf {
+    let file_name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    target.with_file_name(format!(".{file_name}.hdiff-apply.tmp"))
+}
+
+/// Persist the rename itself, only possible on unix where directories can be opened
+fn sync_parent_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        if let Err(e) = File::open(parent).and_then(|dir| dir.sync_all()) {
+            tracing::debug!("Failed to sync {}: {}", parent.display(), e);
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Whether `path` has the size and, if the manifest has one, the MD5 of the entry's source
+fn matches_manifest(path: &Path, entry: &DiffMap) -> bool {
+    if !has_expected_size(path, entry.source_file_size) {
+        return false;
+    }
+
+    entry.source_file_md5.is_empty()
+        || checksum::hash_file(path, Algorithm::Md5)
+            .is_ok_and(|md5| md5.eq_ignore_ascii_case(&entry.source_file_md5))
+}
+
+/// `expected` of 0 means the manifest doesn't say, then only existence is checked
+fn has_expected_size(path: &Path, expected: u64) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) => expected == 0 || metadata.len() == expected,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(source: &str, target: &str) -> DiffMap {
+        DiffMap {
+            source_file_name: source.to_string(),
+            source_file_size: 0,
+            source_file_md5: String::new(),
+            target_file_name: target.to_string(),
+            target_file_size: 0,
+            patch_file_name: format!("{target}.hdiff"),
+            patch_file_size: 0,
+            source_path: PathBuf::from("/game").join(source),
+            target_path: PathBuf::from("/game").join(target),
+            patch_path: PathBuf::from("/game").join(format!("{target}.hdiff")),
+        }
+    }
+
+    fn schedule_of(entries: &[(&str, &str)]) -> Schedule {
+        let diff_map: Vec<DiffMap> = entries.iter().map(|(s, t)| entry(s, t)).collect();
+        schedule(&diff_map)
+    }
+
+    #[test]
+    fn independent_entries_run_together() {
+        let schedule = schedule_of(&[("a", "a"), ("b", "c"), ("d", "e")]);
+        assert_eq!(schedule.waves, vec![vec![0, 1, 2]]);
+        assert!(schedule.copied.is_empty());
+    }
+
+    #[test]
+    fn writer_waits_for_readers_of_its_target() {
+        // 1 overwrites a, which 0 and 2 still have to read
+        let schedule = schedule_of(&[("a", "b"), ("c", "a"), ("a", "d")]);
+        assert_eq!(schedule.waves, vec![vec![0, 2], vec![1]]);
+        assert!(schedule.copied.is_empty());
+    }
+
+    #[test]
+    fn chains_run_in_order() {
+        let schedule = schedule_of(&[("c", "d"), ("b", "c"), ("a", "b")]);
+        assert_eq!(schedule.waves, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn swap_reads_one_side_from_a_copy() {
+        let schedule = schedule_of(&[("a", "b"), ("b", "a")]);
+        assert_eq!(schedule.copied, BTreeSet::from([0]));
+        // 1 may overwrite a right away since 0 reads the copy
+        assert_eq!(schedule.waves, vec![vec![1], vec![0]]);
+    }
+
+    #[test]
+    fn longer_cycle_is_broken_once() {
+        let schedule = schedule_of(&[("a", "b"), ("b", "c"), ("c", "a")]);
+        assert_eq!(schedule.copied.len(), 1);
+        assert_eq!(schedule.waves.iter().map(Vec::len).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn source_copy_is_hidden_next_to_the_source() {
+        assert_eq!(
+            source_copy_path(Path::new("/game/dir/a.pck")),
+            PathBuf::from("/game/dir/.a.pck.hdiff-apply.orig")
+        );
     }
 }
@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    deletefiles::{DeleteFileError, DeleteFiles},
+    hdiffmap::{self, PatchError},
+    package::MANIFEST_FILES,
+    paths::{self, GameDir, PathError},
+    seven_util::{ArchiveEntry, SevenError},
+    utils,
+};
+
+#[derive(Debug, Error)]
+pub enum InspectError {
+    #[error(transparent)]
+    Manifest(#[from] PatchError),
+    #[error(transparent)]
+    DeleteList(#[from] DeleteFileError),
+    #[error(transparent)]
+    Path(#[from] PathError),
+    #[error(transparent)]
+    Seven(#[from] SevenError),
+}
+
+/// Rough grouping of game files by what they contain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Area {
+    Audio,
+    Video,
+    Blocks,
+    Executables,
+    Other,
+}
+
+impl Area {
+    fn of(name: &str) -> Self {
+        let extension = Path::new(name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        match extension.as_str() {
+            "pck" | "wem" | "bnk" => Area::Audio,
+            "usm" | "mp4" | "bk2" => Area::Video,
+            "block" => Area::Blocks,
+            "exe" | "dll" => Area::Executables,
+            _ => Area::Other,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Area::Audio => "audio",
+            Area::Video => "video",
+            Area::Blocks => "asset blocks",
+            Area::Executables => "executables",
+            Area::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    /// Rebuilt from the installed file with an hdiff patch
+    Patched,
+    /// Stored in the package as a whole, either new or replacing the installed file. The
+    /// package alone doesn't tell which
+    Whole,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub change: Change,
+    pub area: Area,
+    /// Bytes of the patch or the file inside the package
+    pub package_size: u64,
+    /// Size of the file once it's written, 0 if the manifest doesn't say
+    pub target_size: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct AreaSummary {
+    pub files: usize,
+    pub package_size: u64,
+    pub target_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageInfo {
+    pub archive: String,
+    pub archive_size: u64,
+    /// Version the client is on after applying the package
+    pub version: String,
+    /// Version the client has to be on, `None` if it can't be told from the package
+    pub source_version: Option<String>,
+    pub patched: usize,
+    /// Files stored whole, new ones and replacements alike
+    pub whole_files: usize,
+    pub deleted: usize,
+    pub patch_size: u64,
+    pub whole_size: u64,
+    pub target_size: u64,
+    pub areas: BTreeMap<Area, AreaSummary>,
+    pub largest: Vec<FileInfo>,
+}
+
+impl PackageInfo {
+    /// Summarize a package from its listing and the manifests extracted to `manifests_dir`,
+    /// keeping the `top` files taking the most space in the package
+    pub fn new(
+        archive: &Path,
+        entries: &[ArchiveEntry],
+        manifests_dir: &Path,
+        version: String,
+        source_version: Option<String>,
+        top: usize,
+    ) -> Result<Self, InspectError> {
+        // Resolving against the manifests directory still rejects paths escaping the install
+        let manifests = GameDir::new(manifests_dir)?;
+        let diff_map =
+            match hdiffmap::load_diff_map(&manifests, &manifests_dir.join(MANIFEST_FILES[0])) {
+                Ok(diff_map) => diff_map,
+                Err(PatchError::NotFound(_)) => vec![],
+                Err(e) => return Err(e.into()),
+            };
+        let deleted = match DeleteFiles::new(&manifests)
+            .listed_files(&manifests_dir.join(MANIFEST_FILES[1]))
+        {
+            Ok(files) => files.len(),
+            Err(DeleteFileError::NotFound(_)) => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        // Manifests and listings spell paths differently, 7z on Windows uses `\\`
+        let key = paths::folded_name;
+        let sizes: HashMap<String, u64> = entries
+            .iter()
+            .filter(|e| !e.is_dir)
+            .map(|e| (key(&e.path), e.size))
+            .collect();
+
+        let mut files: Vec<FileInfo> = diff_map
+            .iter()
+            .map(|entry| {
+                let name = paths::manifest_name(&entry.target_file_name);
+                FileInfo {
+                    area: Area::of(&name),
+                    name,
+                    change: Change::Patched,
+                    package_size: sizes
+                        .get(&key(&entry.patch_file_name))
+                        .copied()
+                        .unwrap_or(entry.patch_file_size),
+                    target_size: entry.target_file_size,
+                }
+            })
+            .collect();
+
+        let skipped: HashSet<String> = diff_map
+            .iter()
+            .map(|entry| key(&entry.patch_file_name))
+            .chain(MANIFEST_FILES.iter().map(|name| key(name)))
+            .collect();
+        files.extend(
+            entries
+                .iter()
+                .filter(|e| !e.is_dir && !skipped.contains(&key(&e.path)))
+                .map(|e| {
+                    let name = paths::manifest_name(&e.path);
+                    FileInfo {
+                        area: Area::of(&name),
+                        name,
+                        change: Change::Whole,
+                        package_size: e.size,
+                        target_size: e.size,
+                    }
+                }),
+        );
+
+        let mut areas: BTreeMap<Area, AreaSummary> = BTreeMap::new();
+        for file in &files {
+            let area = areas.entry(file.area).or_default();
+            area.files += 1;
+            area.package_size += file.package_size;
+            area.target_size += file.target_size;
+        }
+
+        let size_of = |change| -> u64 {
+            files
+                .iter()
+                .filter(|f| f.change == change)
+                .map(|f| f.package_size)
+                .sum()
+        };
+        let patch_size = size_of(Change::Patched);
+        let whole_size = size_of(Change::Whole);
+
+        let patched = diff_map.len();
+        let whole_files = files.len() - patched;
+        let target_size = files.iter().map(|f| f.target_size).sum();
+
+        files.sort_by(|a, b| {
+            b.package_size
+                .cmp(&a.package_size)
+                .then(a.name.cmp(&b.name))
+        });
+        files.truncate(top);
+
+        Ok(Self {
+            archive: archive.display().to_string(),
+            archive_size: archive.metadata().map(|m| m.len()).unwrap_or(0),
+            version,
+            source_version,
+            patched,
+            whole_files,
+            deleted,
+            patch_size,
+            whole_size,
+            target_size,
+            areas,
+            largest: files,
+        })
+    }
+
+    /// Human readable summary
+    pub fn format_table(&self) -> String {
+        let size = utils::format_size;
+        let mut out = String::new();
+
+        out.push_str(&format!(
+            "Package:        {} ({})\n",
+            self.archive,
+            size(self.archive_size)
+        ));
+        out.push_str(&format!("Version:        {}\n", self.version));
+        out.push_str(&format!(
+            "Applies to:     {}\n",
+            self.source_version.as_deref().unwrap_or("unknown")
+        ));
+        out.push_str(&format!(
+            "Patched files:  {} ({} of patches)\n",
+            self.patched,
+            size(self.patch_size)
+        ));
+        out.push_str(&format!(
+            "Whole files:    {} ({}, new or replacing installed ones)\n",
+            self.whole_files,
+            size(self.whole_size)
+        ));
+        out.push_str(&format!("Deleted files:  {}\n", self.deleted));
+        out.push_str(&format!("Written:        {}\n", size(self.target_size)));
+
+        out.push_str(&format!(
+            "\n{:<14}{:>8}{:>14}{:>14}\n",
+            "Area", "Files", "Package", "Written"
+        ));
+        for (area, summary) in &self.areas {
+            out.push_str(&format!(
+                "{:<14}{:>8}{:>14}{:>14}\n",
+                area.name(),
+                summary.files,
+                size(summary.package_size),
+                size(summary.target_size)
+            ));
+        }
+
+        if !self.largest.is_empty() {
+            out.push_str("\nLargest files:\n");
+            for file in &self.largest {
+                let change = match file.change {
+                    Change::Patched => "patched",
+                    Change::Whole => "whole",
+                };
+                out.push_str(&format!(
+                    "{:>12}  {:<8} {}\n",
+                    size(file.package_size),
+                    change,
+                    file.name
+                ));
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn listed(path: &str, size: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            path: path.to_string(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backslash_manifest_matches_listing() {
+        let dir = std::env::temp_dir().join(format!("hdiff-apply-inspect-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("hdiffmap.json"),
+            r#"{"diff_map": [{
+                "source_file_name": "dir\\x.pck",
+                "target_file_name": "dir\\x.pck",
+                "target_file_size": 1000,
+                "patch_file_name": "dir\\x.pck.hdiff",
+                "patch_file_size": 1
+            }]}"#,
+        )
+        .unwrap();
+
+        // 7z on Linux lists entries with `/`, on Windows with `\`
+        let entries = [
+            listed("hdiffmap.json", 10),
+            listed("dir/x.pck.hdiff", 300),
+            listed("dir\\new.usm", 50),
+        ];
+        let info = PackageInfo::new(
+            &dir.join("package.7z"),
+            &entries,
+            &dir,
+            "10.3.1".to_string(),
+            None,
+            10,
+        )
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(info.patched, 1);
+        assert_eq!(info.whole_files, 1);
+        assert_eq!(info.patch_size, 300);
+        assert_eq!(info.whole_size, 50);
+        assert_eq!(info.target_size, 1050);
+
+        let names: Vec<&str> = info.largest.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["dir/x.pck", "dir/new.usm"]);
+        assert_eq!(info.areas[&Area::Audio].files, 1);
+        assert_eq!(info.areas[&Area::Video].files, 1);
+    }
+}
@@ -1,48 +1,287 @@
 use std::{
+    collections::HashSet,
     path::{Path, PathBuf},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
+mod backup;
 mod binary_version;
+mod checksum;
+mod config;
 mod deletefiles;
+mod discovery;
 mod error;
+mod game_process;
 mod hdiffmap;
+mod inspect;
+mod out_of_place;
+mod package;
+mod package_builder;
+mod paths;
+mod reverse;
 mod seven_util;
+mod squash;
+mod tools;
 mod utils;
 mod verifier;
+mod workspace;
 
-use binary_version::BinaryVersion;
-use clap::Parser;
-use deletefiles::DeleteFiles;
-use hdiffmap::HDiffMap;
-use rand::{distr::Alphanumeric, Rng};
+use backup::{Backup, SaveKind, BACKUP_DIR_NAME};
+use binary_version::{BinaryVersion, BINARY_VERSION_PATH};
+use clap::{ArgAction, Parser, Subcommand};
+use config::{
+    BackupPolicy, Config, ConfigLayer, FailurePolicy, LogFormat, LogLayer, MismatchPolicy,
+    RunningGamePolicy, VerifyLevel,
+};
+use deletefiles::{DeleteFileError, DeleteFiles, DeleteOutcome, DeleteReport};
+use hdiffmap::{HDiffMap, PatchError, PatchOptions, PatchReport};
+use inspect::PackageInfo;
+use package::{PackageError, MANIFEST_FILES, STAGING_DIR_NAME};
+use paths::GameDir;
+use reverse::ReversePackage;
 use seven_util::SevenUtil;
-use verifier::Verifier;
+use squash::{Squash, SquashError};
+use tools::{Tool, ToolKind};
+use verifier::{Verifier, VerifyReport};
+use workspace::{InstallLock, Workspace, INSTALL_LOCK_NAME};
 
 type Error = error::Error;
 
-pub const TEMP_DIR_NAME: &'static str = "hdiff-apply";
+/// Regular update as `(version, temp_path, archive_path)`
+type UpdatePackage = (BinaryVersion, PathBuf, PathBuf);
+/// Generated package pinned to one client version as `(base_version, version, temp_path, archive_path)`
+type PinnedPackage = (String, BinaryVersion, PathBuf, PathBuf);
+
+pub const TEMP_DIR_NAME: &str = "hdiff-apply";
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg()]
-    game_path: Option<String>,
+    game_path: Option<PathBuf>,
+    /// Update a discovered install, by its index in `installs` or part of its path
+    #[arg(long, value_name = "INDEX|NAME", conflicts_with = "game_path")]
+    install: Option<String>,
     #[arg(long)]
     skip_version_check: bool, // TODO: impl this
+    /// Directory for extracted manifests
+    #[arg(long)]
+    temp_dir: Option<PathBuf>,
+    /// Write the updated client to this new directory and leave the game directory untouched
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// hpatchz to use instead of the embedded one
+    #[arg(long)]
+    hpatchz_path: Option<PathBuf>,
+    /// 7z to use instead of the embedded one
+    #[arg(long)]
+    sevenzip_path: Option<PathBuf>,
+    /// Also write a package that downgrades back to the current version into this directory
+    #[arg(long, value_name = "DIR")]
+    reverse_dir: Option<PathBuf>,
+    /// hdiffz used to build the reverse package
+    #[arg(long)]
+    hdiffz_path: Option<PathBuf>,
+    /// Number of worker threads (0 = one per CPU core)
+    #[arg(short, long)]
+    jobs: Option<usize>,
+    /// MiB of patch sources processed at the same time (0 = half of the system memory)
+    #[arg(long, value_name = "MIB")]
+    memory_budget: Option<u64>,
+    /// How many times a failed file is retried
+    #[arg(long)]
+    retries: Option<u32>,
+    /// Pre-flight verification level
+    #[arg(long, value_enum)]
+    verify: Option<VerifyLevel>,
+    /// What to do when source files are missing or don't match the manifest
+    #[arg(long, value_enum)]
+    on_mismatch: Option<MismatchPolicy>,
+    /// Which files to back up before modifying them
+    #[arg(long, value_enum)]
+    backup: Option<BackupPolicy>,
+    /// What to do when files of a package fail to patch
+    #[arg(long, value_enum)]
+    on_failure: Option<FailurePolicy>,
+    /// What to do when the game or launcher is running
+    #[arg(long, value_enum)]
+    running_game: Option<RunningGamePolicy>,
+    /// Update even if the game or launcher is running
+    #[arg(long)]
+    force: bool,
+    /// Write the log to this file instead of hdiff-apply.log in the game directory
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Don't write a log file
+    #[arg(long, conflicts_with = "log_file")]
+    no_log_file: bool,
+    /// Format of the log file
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+    /// More console output, can be repeated
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Less console output, can be repeated
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the effective configuration merged from config files, environment and flags
+    Config,
+    /// List game installs found in launcher directories and Wine prefixes
+    Installs,
+    /// Restore the game files saved before the last update (requires --backup affected)
+    Rollback,
+    /// Combine the chain of packages in the game directory into a single package
+    Squash {
+        /// Directory to write the package to, also used for the intermediate clients
+        output: PathBuf,
+        /// Apply the squashed package to another copy and compare it with the result of
+        /// applying the packages one by one
+        #[arg(long)]
+        check: bool,
+    },
+    /// Describe an update package without applying it
+    Inspect {
+        archive: PathBuf,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+        /// Number of largest files to list
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+}
+
+impl Args {
+    fn config_layer(&self) -> ConfigLayer {
+        ConfigLayer {
+            game_path: self.game_path.clone(),
+            temp_dir: self.temp_dir.clone(),
+            output_dir: self.output_dir.clone(),
+            hpatchz_path: self.hpatchz_path.clone(),
+            sevenzip_path: self.sevenzip_path.clone(),
+            reverse_dir: self.reverse_dir.clone(),
+            hdiffz_path: self.hdiffz_path.clone(),
+            jobs: self.jobs,
+            memory_budget: self.memory_budget,
+            retries: self.retries,
+            verify: self.verify,
+            on_mismatch: self.on_mismatch,
+            backup: self.backup,
+            on_failure: self.on_failure,
+            running_game: self.running_game,
+            log: LogLayer {
+                file: (self.no_log_file || self.log_file.is_some()).then_some(!self.no_log_file),
+                path: self.log_file.clone(),
+                format: self.log_format,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
 }
 
 fn run() -> Result<(), Error> {
-    utils::init_tracing();
+    let args = Args::parse();
+    let mut config = Config::load(args.config_layer())?;
+    config.log.level = config
+        .log
+        .level
+        .adjust(args.verbose as i8 - args.quiet as i8);
+
+    match args.command {
+        Some(Command::Config) => {
+            for source in &config.sources {
+                println!("# Loaded {}", source.display());
+            }
+            print!("{}", config.to_toml());
+            return Ok(());
+        }
+        Some(Command::Installs) => {
+            let installs = discovery::discover();
+            if installs.is_empty() {
+                println!("No installs found");
+            }
+            print!("{}", discovery::format_list(&installs));
+            return Ok(());
+        }
+        Some(Command::Rollback) => return rollback(&config, &args),
+        Some(Command::Squash { ref output, check }) => {
+            return squash_chain(&config, &args, output, check)
+        }
+        Some(Command::Inspect {
+            ref archive,
+            json,
+            top,
+        }) => return inspect_package(&config, archive, json, top),
+        None => {}
+    }
+
+    let source_path = utils::determine_game_path(&config, args.install.as_deref())?;
+    // Everything that is written goes to the output directory when there is one
+    let game_path = match &config.output_dir {
+        Some(output_dir) => {
+            out_of_place::prepare(&source_path, output_dir)?;
+            output_dir.clone()
+        }
+        None => source_path.clone(),
+    };
+    let log_path = utils::init_tracing(&config.log, &game_path)?;
 
     utils::set_console_title()?;
-    utils::clean_temp_hdiff_data()?;
 
-    let args = Args::parse();
+    if let Some(log_path) = log_path {
+        tracing::debug!("Logging to {}", log_path.display());
+    }
+    tracing::info!("Game directory: {}", source_path.display());
+    if config.output_dir.is_some() {
+        tracing::info!("Writing the updated client to {}", game_path.display());
+    }
+
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.jobs)
+        .build_global()
+    {
+        tracing::warn!("Failed to configure thread pool: {}", e);
+    }
 
-    let temp_dir_path = utils::get_and_create_temp_dir()?;
-    let hpatchz_path = utils::get_hpatchz()?;
-    let game_path = utils::determine_game_path(args.game_path)?;
-    let update_archives_paths = utils::get_update_archives(&game_path)?;
+    let temp_dir_path = utils::get_and_create_temp_dir(&config)?;
+    Workspace::clean_stale(&temp_dir_path);
+    let workspace = Workspace::create(&temp_dir_path)?;
+    let tools_dir = tools::private_dir()?;
+    SevenUtil::init(tools::locate(
+        ToolKind::SevenZip,
+        config.sevenzip_path.as_deref(),
+        &tools_dir,
+    )?);
+
+    let hpatchz = tools::locate(
+        ToolKind::Hpatchz,
+        config.hpatchz_path.as_deref(),
+        &tools_dir,
+    )?;
+    // Look for hdiffz now so a missing one doesn't show up only after the update
+    let hdiffz = match &config.reverse_dir {
+        Some(_) => Some(tools::locate(
+            ToolKind::Hdiffz,
+            config.hdiffz_path.as_deref(),
+            &tools_dir,
+        )?),
+        None => None,
+    };
+    let source_dir = GameDir::new(&source_path)?;
+    let _install_lock = InstallLock::acquire(&game_path)?;
+    // The client is copied from the game directory, an in-place update running there at
+    // the same time would leave a mix of both versions in the output
+    let _source_lock = match &config.output_dir {
+        Some(_) => Some(InstallLock::acquire_source(&source_path)?),
+        None => None,
+    };
+    let update_archives_paths = utils::get_update_archives(&source_path)?;
 
     // Commented out because its not implemented yet
     //if args.skip_version_check {
@@ -51,41 +290,179 @@ fn run() -> Result<(), Error> {
 
     tracing::info!("Preparing for update...");
 
-    // <(hdiff_version, temp_path, archive_path)>
-    let mut updates_big_vec: Vec<(BinaryVersion, PathBuf, PathBuf)> = vec![];
+    let (mut updates_big_vec, pinned_packages) =
+        read_packages(workspace.path(), &update_archives_paths)?;
 
-    // Prepare hdiffs by storing thier paths and versions
-    for update_archive in &update_archives_paths {
-        let rnd_name: String = rand::rng()
-            .sample_iter(&Alphanumeric)
-            .take(5)
-            .map(char::from)
-            .collect();
+    // Do some checks to make sure client doesn't brick :)
+    let client_version = BinaryVersion::parse(&source_dir.resolve(BINARY_VERSION_PATH)?)?;
+
+    // Regular updates win over a generated package left in the game directory
+    let has_update = updates_big_vec
+        .iter()
+        .any(|(version, _, _)| utils::verify_hdiff_version(&client_version, version));
+    let (start_index, sequence) = match pinned_chain(&client_version, pinned_packages) {
+        chain if chain.is_empty() || has_update => {
+            update_sequence(&client_version, &updates_big_vec)?
+        }
+        chain => {
+            let mut sequence = client_version.to_string();
+            for (version, _, _) in &chain {
+                sequence.push_str(&format!(" -> {}", version));
+            }
+            updates_big_vec = chain;
+            (Some(0), sequence)
+        }
+    };
+
+    let update_choice = {
+        print!(
+            "Proceed with this update sequence: {} [Yes/No (default: {})]: ",
+            sequence,
+            if config.confirm_default { "Yes" } else { "No" }
+        );
+        utils::wait_for_confirmation(config.confirm_default)
+    };
+
+    let now = Instant::now();
+
+    if update_choice {
+        if let Some(index) = start_index {
+            // Test every selected archive before anything in the game directory is touched
+            if config.verify != VerifyLevel::None {
+                for (_, _, archive_path) in updates_big_vec.iter().skip(index) {
+                    tracing::info!("Testing {}", archive_path.display());
+
+                    if checksum::verify_sidecar(archive_path)? {
+                        tracing::info!("Checksum of {} matches", archive_path.display());
+                    }
+                    SevenUtil::inst().test_archive(archive_path)?;
+                }
+            }
 
-        let temp_path = temp_dir_path.join(format!("hdiff_{}", rnd_name));
+            let game_dir = match &config.output_dir {
+                Some(_) => {
+                    materialize_output(&source_path, &game_path, &update_archives_paths)?;
+                    GameDir::new(&game_path)?
+                }
+                None => source_dir,
+            };
+
+            // Inspect what every archive would write before extracting any of them
+            for (_, temp_path, archive_path) in updates_big_vec.iter().skip(index) {
+                check_package(&game_dir, temp_path, archive_path)?;
+            }
+
+            let skip_sets = match config.verify {
+                VerifyLevel::None => vec![HashSet::new(); updates_big_vec.len() - index],
+                _ => verify_sources(&config, &game_dir, &updates_big_vec[index..])?,
+            };
+
+            game_process::ensure_not_running(&game_dir, config.running_game, args.force)?;
+
+            let backup = match config.backup {
+                BackupPolicy::None if config.reverse_dir.is_none() => {
+                    Backup::new(&game_dir).discard()?;
+                    None
+                }
+                // The reverse package is built from the backup
+                BackupPolicy::None | BackupPolicy::Affected => {
+                    let backup = Backup::new(&game_dir);
+                    backup.begin(&client_version.to_string())?;
+                    Some(backup)
+                }
+            };
+
+            for ((_, temp_path, archive_path), skip) in
+                updates_big_vec.iter().skip(index).zip(&skip_sets)
+            {
+                run_updater(
+                    &config,
+                    &game_dir,
+                    &hpatchz,
+                    archive_path,
+                    temp_path,
+                    backup.as_ref(),
+                    skip,
+                )?;
+            }
+            if let Some(backup) = &backup {
+                let updated =
+                    BinaryVersion::parse(&game_dir.resolve(BINARY_VERSION_PATH)?)?.to_string();
+                backup.finish(&updated)?;
+            }
+
+            if let (Some(reverse_dir), Some(hdiffz), Some(backup)) =
+                (&config.reverse_dir, &hdiffz, &backup)
+            {
+                let updated_version = &updates_big_vec[updates_big_vec.len() - 1].0;
+                tracing::info!(
+                    "Building reverse package from {} to {}",
+                    updated_version,
+                    client_version
+                );
+
+                let report = ReversePackage::new(&game_dir, backup, hdiffz).build(
+                    updated_version,
+                    &client_version,
+                    workspace.path(),
+                    reverse_dir,
+                )?;
+                tracing::info!(
+                    "Wrote {} ({} diffs, {} full files, {} deletions)",
+                    report.archive.display(),
+                    report.diffs,
+                    report.full_files,
+                    report.deleted
+                );
+            }
+        }
+    }
+
+    tracing::info!("Updated in {:.2?}", now.elapsed());
+    utils::wait_for_input();
+    Ok(())
+}
+
+/// Extract the version and manifests of every archive into `workspace_path` and split
+/// them into regular updates, sorted by version, and pinned packages
+fn read_packages(
+    workspace_path: &Path,
+    archives: &[PathBuf],
+) -> Result<(Vec<UpdatePackage>, Vec<PinnedPackage>), Error> {
+    let mut updates_big_vec: Vec<UpdatePackage> = vec![];
+    let mut pinned_packages: Vec<PinnedPackage> = vec![];
+
+    // Prepare hdiffs by storing thier paths and versions
+    for (i, update_archive) in archives.iter().enumerate() {
+        let temp_path = workspace_path.join(format!("hdiff_{}", i));
 
         SevenUtil::inst().extract_specific_files_to(
-            &update_archive,
-            &[
-                "StarRail_Data\\StreamingAssets\\BinaryVersion.bytes",
-                "hdiffmap.json",
-                "deletefiles.txt",
-            ],
+            update_archive,
+            &[BINARY_VERSION_PATH, MANIFEST_FILES[0], MANIFEST_FILES[1]],
             &temp_path,
         )?;
 
         let hdiff_version = BinaryVersion::parse(&temp_path.join("BinaryVersion.bytes"))?;
 
-        updates_big_vec.push((hdiff_version, temp_path, update_archive.to_path_buf()));
+        match package_builder::base_version(&temp_path.join(MANIFEST_FILES[0])) {
+            Some(base) => {
+                pinned_packages.push((base, hdiff_version, temp_path, update_archive.to_path_buf()))
+            }
+            None => updates_big_vec.push((hdiff_version, temp_path, update_archive.to_path_buf())),
+        }
     }
     updates_big_vec.sort_by(|a, b| a.0.cmp(&b.0));
 
-    // Do some checks to make sure client doesn't brick :)
-    let client_version = BinaryVersion::parse(
-        &game_path.join("StarRail_Data\\StreamingAssets\\BinaryVersion.bytes"),
-    )?;
+    Ok((updates_big_vec, pinned_packages))
+}
 
-    let mut prev_version = &client_version;
+/// Find where the chain of regular updates starts for `client_version` and describe it,
+/// every package after the first one has to follow its predecessor
+fn update_sequence(
+    client_version: &BinaryVersion,
+    updates_big_vec: &[UpdatePackage],
+) -> Result<(Option<usize>, String), Error> {
+    let mut prev_version = client_version;
     let mut start_index = None;
 
     for (i, (hdiff_version, _, _)) in updates_big_vec.iter().enumerate() {
@@ -120,13 +497,13 @@ fn run() -> Result<(), Error> {
     // Everything is correct proceeding further
     let mut sequence = String::new();
     let mut found_start = false;
-    let mut prev_version = &client_version;
+    let mut prev_version = client_version;
 
     for (version, _, _) in updates_big_vec.iter() {
         if !found_start {
             if utils::verify_hdiff_version(prev_version, version) {
                 found_start = true;
-                sequence.push_str(&format!("{}", prev_version.to_string()));
+                sequence.push_str(&prev_version.to_string());
             } else {
                 continue;
             }
@@ -136,79 +513,544 @@ fn run() -> Result<(), Error> {
         prev_version = version;
     }
 
-    let update_choice = {
-        print!(
-            "Proceed with this update sequence: {} [Yes/No (default: Yes)]: ",
-            sequence
-        );
-        utils::wait_for_confirmation(true)
+    Ok((start_index, sequence))
+}
+
+/// Pinned packages leading away from `client_version` one after another
+fn pinned_chain(
+    client_version: &BinaryVersion,
+    mut pinned_packages: Vec<PinnedPackage>,
+) -> Vec<UpdatePackage> {
+    let mut chain = vec![];
+    let mut current = client_version.to_string();
+
+    while let Some(i) = pinned_packages
+        .iter()
+        .position(|(base, _, _, _)| *base == current)
+    {
+        let (_, version, temp_path, archive_path) = pinned_packages.swap_remove(i);
+        current = version.to_string();
+        chain.push((version, temp_path, archive_path));
+    }
+
+    chain
+}
+
+/// Undo the last update of the install using its backup
+fn rollback(config: &Config, args: &Args) -> Result<(), Error> {
+    let game_path = utils::determine_game_path(config, args.install.as_deref())?;
+    utils::init_tracing(&config.log, &game_path)?;
+    tracing::info!("Game directory: {}", game_path.display());
+
+    let game_dir = GameDir::new(&game_path)?;
+    let _install_lock = InstallLock::acquire(&game_path)?;
+    game_process::ensure_not_running(&game_dir, config.running_game, args.force)?;
+
+    let now = Instant::now();
+    let version = BinaryVersion::parse(&game_dir.resolve(BINARY_VERSION_PATH)?)?;
+    let report = Backup::new(&game_dir).rollback(&version.to_string())?;
+    tracing::info!(
+        "Rolled back in {:.2?}: restored {} files, removed {} added by the update and {} empty directories",
+        now.elapsed(),
+        report.restored,
+        report.removed,
+        report.pruned_dirs
+    );
+    Ok(())
+}
+
+/// Apply the chain of packages to a copy of the client and turn the difference into
+/// a single package, optionally checking it against the sequential result
+fn squash_chain(config: &Config, args: &Args, output: &Path, check: bool) -> Result<(), Error> {
+    let source_path = utils::determine_game_path(config, args.install.as_deref())?;
+    std::fs::create_dir_all(output)?;
+    utils::init_tracing(&config.log, output)?;
+    tracing::info!("Game directory: {}", source_path.display());
+
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.jobs)
+        .build_global()
+    {
+        tracing::warn!("Failed to configure thread pool: {}", e);
+    }
+
+    let temp_dir_path = utils::get_and_create_temp_dir(config)?;
+    Workspace::clean_stale(&temp_dir_path);
+    let workspace = Workspace::create(&temp_dir_path)?;
+    let tools_dir = tools::private_dir()?;
+    SevenUtil::init(tools::locate(
+        ToolKind::SevenZip,
+        config.sevenzip_path.as_deref(),
+        &tools_dir,
+    )?);
+    let hpatchz = tools::locate(
+        ToolKind::Hpatchz,
+        config.hpatchz_path.as_deref(),
+        &tools_dir,
+    )?;
+    let hdiffz = tools::locate(ToolKind::Hdiffz, config.hdiffz_path.as_deref(), &tools_dir)?;
+
+    let source_dir = GameDir::new(&source_path)?;
+    // Keeps updates out of the game directory while the client is copied from it
+    let _install_lock = InstallLock::acquire_source(&source_path)?;
+    let archives = utils::get_update_archives(&source_path)?;
+    let (updates, _) = read_packages(workspace.path(), &archives)?;
+    let client_version = BinaryVersion::parse(&source_dir.resolve(BINARY_VERSION_PATH)?)?;
+    let (start_index, sequence) = update_sequence(&client_version, &updates)?;
+    let chain = &updates[start_index.unwrap_or_default()..];
+    let Some((final_version, _, _)) = chain.last() else {
+        return Err(Error::ArchiveNotFound());
     };
+    tracing::info!("Squashing {}", sequence);
 
     let now = Instant::now();
+    // Patch failures would end up in the package, and nothing here needs a backup
+    let apply_config = |dir: &Path| Config {
+        output_dir: Some(dir.to_path_buf()),
+        backup: BackupPolicy::None,
+        on_failure: FailurePolicy::Abort,
+        // A package built from modified files would carry the modifications along
+        on_mismatch: MismatchPolicy::Abort,
+        ..config.clone()
+    };
 
-    if update_choice {
-        if let Some(index) = start_index {
-            for (_, temp_path, archive_path) in updates_big_vec.iter().skip(index) {
-                
-                let hdiffmap_path = temp_path.join("hdiffmap.json");
-                let deletefiles_path = temp_path.join("deletefiles.txt");
-                
-                let verifier = Verifier::new(game_path.as_path(), &hdiffmap_path);
-                if !verifier.by_file_size()? {
-                    tracing::error!("Size mismatch"); // TODO: return error with message
-                }
+    let result_path = output.join("squash-result");
+    let _ = std::fs::remove_dir_all(&result_path);
+    out_of_place::prepare(&source_path, &result_path)?;
+    materialize_output(&source_path, &result_path, &archives)?;
+    let result_dir = GameDir::new(&result_path)?;
 
-                run_updater(
-                    &game_path,
-                    &hpatchz_path,
-                    archive_path,
-                    &hdiffmap_path,
-                    &deletefiles_path,
-                )?;
-            }
+    // Inspect what every archive would write before extracting any of them
+    for (_, temp_path, archive_path) in chain {
+        check_package(&result_dir, temp_path, archive_path)?;
+    }
+
+    if config.verify != VerifyLevel::None {
+        verify_sources(&apply_config(&result_path), &result_dir, chain)?;
+    }
+
+    for (_, temp_path, archive_path) in chain {
+        run_updater(
+            &apply_config(&result_path),
+            &result_dir,
+            &hpatchz,
+            archive_path,
+            temp_path,
+            None,
+            &HashSet::new(),
+        )?;
+    }
+
+    let packages: Vec<(&Path, &Path)> = chain
+        .iter()
+        .map(|(_, temp_path, archive_path)| (temp_path.as_path(), archive_path.as_path()))
+        .collect();
+    let touched = package::touched_files(&source_dir, &packages)?;
+
+    tracing::info!("Building the package from {} touched files", touched.len());
+    let report = Squash::new(&source_dir, &result_dir).build(
+        &touched,
+        &client_version,
+        &hdiffz,
+        &workspace.path().join("squash"),
+        &output.join(format!("squash_{}_to_{}.7z", client_version, final_version)),
+    )?;
+    tracing::info!(
+        "Wrote {} ({} diffs, {} full files, {} deletions)",
+        report.archive.display(),
+        report.diffs,
+        report.full_files,
+        report.deleted
+    );
+
+    if check {
+        tracing::info!("Applying the squashed package to another copy to check it");
+
+        let check_path = output.join("squash-check");
+        let _ = std::fs::remove_dir_all(&check_path);
+        out_of_place::prepare(&source_path, &check_path)?;
+        materialize_output(&source_path, &check_path, &archives)?;
+        let check_dir = GameDir::new(&check_path)?;
+
+        let manifests_path = workspace.path().join("squash-manifests");
+        SevenUtil::inst().extract_specific_files_to(
+            &report.archive,
+            &MANIFEST_FILES,
+            &manifests_path,
+        )?;
+        run_updater(
+            &apply_config(&check_path),
+            &check_dir,
+            &hpatchz,
+            &report.archive,
+            &manifests_path,
+            None,
+            &HashSet::new(),
+        )?;
+
+        let mismatches = squash::compare(&result_dir, &check_dir, &touched)?;
+        if !mismatches.is_empty() {
+            return Err(SquashError::Mismatch(
+                mismatches.iter().map(|m| format!("    {m}\n")).collect(),
+            )
+            .into());
         }
+        tracing::info!("The squashed package matches applying the packages one by one");
+        std::fs::remove_dir_all(&check_path)?;
     }
 
-    tracing::info!("Updated in {:.2?}", now.elapsed());
-    utils::wait_for_input();
+    std::fs::remove_dir_all(&result_path)?;
+    tracing::info!("Squashed in {:.2?}", now.elapsed());
+    Ok(())
+}
+
+/// Link the current client into the output directory, leaving out the update archives
+/// and everything hdiff-apply keeps in the game directory
+fn materialize_output(source: &Path, output: &Path, archives: &[PathBuf]) -> Result<(), Error> {
+    let mut skip: Vec<PathBuf> = [
+        utils::LOG_FILE_NAME,
+        INSTALL_LOCK_NAME,
+        BACKUP_DIR_NAME,
+        STAGING_DIR_NAME,
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect();
+
+    for archive in archives {
+        let sidecar = checksum::find_sidecar(archive).map(|(_, sidecar)| sidecar);
+        skip.extend(
+            [Some(archive.as_path()), sidecar.as_deref()]
+                .into_iter()
+                .flatten()
+                .filter_map(Path::file_name)
+                .map(PathBuf::from),
+        );
+    }
+
+    tracing::info!("Linking the current client into {}", output.display());
+    let report = out_of_place::materialize(source, output, &skip)?;
+    tracing::info!(
+        "Linked {} files ({} reflinked, {} hardlinked, {} copied)",
+        report.total(),
+        report.reflinked,
+        report.hardlinked,
+        report.copied
+    );
+
+    Ok(())
+}
+
+/// Check an archive listing and its manifests for anything that could write outside
+/// of the game directory or contradict itself
+fn check_package(game_dir: &GameDir, temp_path: &Path, archive_path: &Path) -> Result<(), Error> {
+    let archive_name = archive_path.display().to_string();
+    let diff_map = match hdiffmap::load_diff_map(game_dir, &temp_path.join("hdiffmap.json")) {
+        Ok(diff_map) => diff_map,
+        Err(PatchError::NotFound(_)) => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    let deleted_files =
+        match DeleteFiles::new(game_dir).listed_files(&temp_path.join("deletefiles.txt")) {
+            Ok(files) => files,
+            Err(DeleteFileError::NotFound(_)) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+    let referenced_files: Vec<String> = diff_map
+        .iter()
+        .flat_map(|entry| entry.file_names().map(str::to_string))
+        .collect();
+    let entries = SevenUtil::inst().list(archive_path)?;
+
+    package::check_extraction(game_dir, &archive_name, &entries, &referenced_files)?;
+
+    let conflicts = package::find_conflicts(&diff_map, &deleted_files, &entries);
+    if !conflicts.is_empty() {
+        for conflict in &conflicts {
+            tracing::error!("{}", conflict);
+        }
+        return Err(PackageError::Inconsistent {
+            archive: archive_name,
+            count: conflicts.len(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn inspect_package(config: &Config, archive: &Path, json: bool, top: usize) -> Result<(), Error> {
+    let temp_dir_path = utils::get_and_create_temp_dir(config)?;
+    Workspace::clean_stale(&temp_dir_path);
+    let workspace = Workspace::create(&temp_dir_path)?;
+    let tools_dir = tools::private_dir()?;
+    SevenUtil::init(tools::locate(
+        ToolKind::SevenZip,
+        config.sevenzip_path.as_deref(),
+        &tools_dir,
+    )?);
+
+    let manifests_path = workspace.path().join("inspect");
+    SevenUtil::inst().extract_specific_files_to(
+        archive,
+        &[BINARY_VERSION_PATH, MANIFEST_FILES[0], MANIFEST_FILES[1]],
+        &manifests_path,
+    )?;
+    let version = BinaryVersion::parse(&manifests_path.join("BinaryVersion.bytes"))?;
+
+    // Official updates go from the previous patch version, the first one of a minor
+    // version comes from a release we can't tell
+    let source_version = package_builder::base_version(&manifests_path.join(MANIFEST_FILES[0]))
+        .or_else(|| {
+            (version.patch_version > 0).then(|| {
+                format!(
+                    "{}.{}.{}",
+                    version.major_version,
+                    version.minor_version,
+                    version.patch_version - 1
+                )
+            })
+        });
+
+    let entries = SevenUtil::inst().list(archive)?;
+    let info = PackageInfo::new(
+        archive,
+        &entries,
+        &manifests_path,
+        version.to_string(),
+        source_version,
+        top,
+    )?;
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&info).unwrap_or_default()
+        );
+    } else {
+        print!("{}", info.format_table());
+    }
     Ok(())
 }
 
+/// Check the sources of every package in the chain before anything is modified and
+/// decide what to do about bad ones. Returns the sources to leave out for each package
+fn verify_sources(
+    config: &Config,
+    game_dir: &GameDir,
+    chain: &[UpdatePackage],
+) -> Result<Vec<HashSet<PathBuf>>, Error> {
+    let mut written_earlier = HashSet::new();
+    let mut reports: Vec<VerifyReport> = vec![];
+
+    for (_, temp_path, archive_path) in chain {
+        tracing::info!(
+            "Verifying the files patched by {}",
+            paths::display_name(archive_path)
+        );
+
+        let report = Verifier::new(game_dir, &temp_path.join(MANIFEST_FILES[0]))
+            .verify(config.verify, &written_earlier)?;
+        for mismatch in &report.mismatches {
+            tracing::error!("{}: {}", mismatch.source_file_name, mismatch.problem);
+        }
+        reports.push(report);
+
+        // Later packages read what this one writes, that can only be checked afterwards
+        for name in package::touched_files(game_dir, &[(temp_path, archive_path)])? {
+            written_earlier.insert(game_dir.resolve(&name)?);
+        }
+    }
+
+    let checked: usize = reports.iter().map(|r| r.checked).sum();
+    let bad: usize = reports.iter().map(|r| r.mismatches.len()).sum();
+    if bad == 0 {
+        tracing::info!("All {} source files match the manifests", checked);
+        return Ok(vec![HashSet::new(); chain.len()]);
+    }
+
+    let skip = match config.on_mismatch {
+        MismatchPolicy::Abort => false,
+        MismatchPolicy::Skip => true,
+        MismatchPolicy::Prompt => {
+            print!(
+                "{} of {} source files are missing or modified. Skip them and patch the rest? [Yes/No (default: No)]: ",
+                bad, checked
+            );
+            utils::wait_for_confirmation(false)
+        }
+    };
+    if !skip {
+        return Err(Error::SourceMismatch(bad, checked));
+    }
+
+    tracing::warn!("Leaving {} mismatched source files unpatched", bad);
+
+    // Entries reading the output of a skipped entry from an earlier package can't work either
+    let mut unpatched = HashSet::new();
+    let mut skip_sets = vec![];
+    for (report, (_, temp_path, _)) in reports.iter().zip(chain) {
+        let mut skip = report.bad_sources();
+        let diff_map = match hdiffmap::load_diff_map(game_dir, &temp_path.join(MANIFEST_FILES[0])) {
+            Ok(diff_map) => diff_map,
+            Err(PatchError::NotFound(_)) => vec![],
+            Err(e) => return Err(e.into()),
+        };
+        for entry in diff_map {
+            if skip.contains(&entry.source_path) || unpatched.contains(&entry.source_path) {
+                skip.insert(entry.source_path);
+                unpatched.insert(entry.target_path);
+            }
+        }
+        skip_sets.push(skip);
+    }
+
+    Ok(skip_sets)
+}
+
 fn run_updater(
-    game_path: &PathBuf,
-    hpatchz_path: &PathBuf,
-    hdiff_archive: &PathBuf,
-    hdiffmap_path: &Path,
-    deletefiles_path: &Path,
+    config: &Config,
+    game_dir: &GameDir,
+    hpatchz: &Tool,
+    hdiff_archive: &Path,
+    manifests_dir: &Path,
+    backup: Option<&Backup>,
+    skip: &HashSet<PathBuf>,
 ) -> Result<(), Error> {
-    let archive_str = &hdiff_archive.display().to_string();
-    let archive_name = archive_str.split('\\').last().unwrap_or("hdiff");
+    let archive_name = &paths::display_name(hdiff_archive);
+    let hdiffmap_path = &manifests_dir.join(MANIFEST_FILES[0]);
+    let deletefiles_path = &manifests_dir.join(MANIFEST_FILES[1]);
 
-    tracing::info!("Extracting {}", archive_name);
-    SevenUtil::inst().extract_hdiff_to(&hdiff_archive, &game_path)?;
+    let delete_files = DeleteFiles::new(game_dir);
+    let hdiff_map = HDiffMap::new(
+        game_dir,
+        hpatchz,
+        hdiff_archive,
+        PatchOptions {
+            memory_budget: config.memory_budget_bytes(),
+            retries: config.retries,
+            retry_delay: Duration::from_millis(config.retry_delay_ms),
+            backup,
+            skip,
+        },
+    );
+
+    let entries = SevenUtil::inst().list(hdiff_archive)?;
 
-    let mut delete_files = DeleteFiles::new(&game_path);
-    if let Err(e) = delete_files.remove(deletefiles_path) {
-        tracing::error!("{}", e);
+    if let Some(backup) = backup {
+        tracing::info!("Backing up files affected by {}", archive_name);
+
+        let mut affected = hdiff_map.affected_files(hdiffmap_path)?;
+        if deletefiles_path.exists() {
+            affected.extend(delete_files.listed_files(deletefiles_path)?);
+        }
+        // Extraction overwrites existing files and adds new ones
+        affected.extend(
+            entries
+                .iter()
+                .filter(|e| !e.is_dir && !MANIFEST_FILES.contains(&e.path.as_str()))
+                .map(|e| e.path.clone()),
+        );
+
+        let (mut reflinked, mut copied) = (0, 0);
+        for name in &affected {
+            match backup.save(name)? {
+                SaveKind::Reflink => reflinked += 1,
+                SaveKind::Copy => copied += 1,
+                SaveKind::Created | SaveKind::Skipped => {}
+            }
+        }
+        tracing::info!(
+            "Snapshotted {} files ({} reflinked, {} copied)",
+            reflinked + copied,
+            reflinked,
+            copied
+        );
     }
 
-    let mut hdiff_map = HDiffMap::new(&game_path, &hpatchz_path);
-    if let Err(e) = hdiff_map.patch(hdiffmap_path) {
-        tracing::error!("{}", e);
+    tracing::info!("Extracting {}", archive_name);
+    let case_mismatch = package::has_case_mismatch(game_dir, &entries);
+    if config.output_dir.is_some() {
+        out_of_place::detach(game_dir, &entries)?;
     }
+    if case_mismatch {
+        tracing::info!("Paths in {} differ in case from the install", archive_name);
+        let staging = game_dir.path().join(STAGING_DIR_NAME);
+        // Left behind by an interrupted run
+        let _ = std::fs::remove_dir_all(&staging);
+        SevenUtil::inst().extract_hdiff_to(hdiff_archive, &staging)?;
+        package::place_extracted(game_dir, &staging, &entries)?;
+    } else {
+        SevenUtil::inst().extract_hdiff_to(hdiff_archive, game_dir.path())?;
+    }
+
+    let delete_report = match delete_files.remove(deletefiles_path) {
+        Ok(report) => report,
+        Err(e @ DeleteFileError::NotFound(_)) => {
+            tracing::error!("{}", e);
+            DeleteReport::default()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let report = match hdiff_map.patch(hdiffmap_path) {
+        Ok(report) => report,
+        Err(PatchError::NotFound(e)) => {
+            tracing::warn!("{} doesn't exist, nothing to patch", e);
+            PatchReport::default()
+        }
+        Err(e) => return Err(e.into()),
+    };
 
-    if delete_files.count() > 0 {
+    if !delete_report.entries.is_empty() {
         tracing::info!(
-            "Deleted {} files listed in deletefiles.txt",
-            delete_files.count()
-        )
+            "Deleted {} files listed in deletefiles.txt ({} freed), {} already gone, {} failed",
+            delete_report.deleted(),
+            utils::format_size(delete_report.freed_bytes()),
+            delete_report.already_gone(),
+            delete_report.failed().count()
+        );
+        for entry in delete_report.failed() {
+            if let DeleteOutcome::Failed(e) = &entry.outcome {
+                tracing::error!("Failed to delete {}: {}", entry.file_name, e);
+            }
+        }
+        if delete_report.pruned_dirs > 0 {
+            tracing::info!("Removed {} empty directories", delete_report.pruned_dirs);
+        }
     }
 
-    if hdiff_map.count() > 0 {
-        tracing::info!(
-            "Patched {} files listed in hdiffmap.json",
-            hdiff_map.count()
-        )
+    if report.patched() > 0 {
+        tracing::info!("Patched {} files listed in hdiffmap.json", report.patched())
+    }
+    if report.skipped > 0 {
+        tracing::warn!(
+            "Skipped {} files listed in hdiffmap.json because of mismatched sources",
+            report.skipped
+        );
+    }
+
+    if !report.is_success() {
+        for entry in report.failed() {
+            if let Err(e) = &entry.result {
+                tracing::error!("{}: {}", entry.target_file_name, e);
+            }
+        }
+
+        let failed = report.failed().count();
+        match config.on_failure {
+            FailurePolicy::Abort => {
+                return Err(Error::PatchFailed(
+                    failed,
+                    report.entries.len(),
+                    archive_name.to_string(),
+                ))
+            }
+            FailurePolicy::Continue => tracing::warn!(
+                "{} files failed to patch, continuing because of --on-failure continue",
+                failed
+            ),
+        }
     }
 
     Ok(())
@@ -216,7 +1058,12 @@ fn run_updater(
 
 fn main() {
     if let Err(e) = run() {
-        tracing::error!("{}", e);
+        // Config errors happen before tracing is set up
+        if tracing::dispatcher::has_been_set() {
+            tracing::error!("{}", e);
+        } else {
+            eprintln!("{}", e);
+        }
         utils::wait_for_input()
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    package::MANIFEST_FILES,
+    paths::{GameDir, PathError},
+    seven_util::ArchiveEntry,
+    utils::LOG_FILE_NAME,
+    workspace::INSTALL_LOCK_NAME,
+};
+
+#[derive(Debug, Error)]
+pub enum OutputError {
+    #[error("Output directory {0} is not empty")]
+    NotEmpty(String),
+    #[error("Output directory {0} can't be inside the game directory {1} or contain it")]
+    Nested(String, String),
+    #[error("Failed to create {0}: {1}")]
+    Io(String, #[source] io::Error),
+    #[error(transparent)]
+    Path(#[from] PathError),
+}
+
+/// How a file ended up in the output directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    /// Copy-on-write clone sharing the data blocks
+    Reflink,
+    Hardlink,
+    Copy,
+}
+
+#[derive(Debug, Default)]
+pub struct LinkReport {
+    pub reflinked: usize,
+    pub hardlinked: usize,
+    pub copied: usize,
+}
+
+impl LinkReport {
+    pub fn total(&self) -> usize {
+        self.reflinked + self.hardlinked + self.copied
+    }
+}
+
+/// Clone `src` to `dst` without copying its data when the filesystem allows it
+#[cfg(target_os = "linux")]
+pub fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs::File::open(src)?;
+    let target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+
+    // SAFETY: both descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        let e = io::Error::last_os_error();
+        drop(target);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Reflink, hardlink or, when neither works, copy `src` to `dst`
+pub fn link_file(src: &Path, dst: &Path) -> io::Result<LinkKind> {
+    if reflink(src, dst).is_ok() {
+        return Ok(LinkKind::Reflink);
+    }
+    if fs::hard_link(src, dst).is_ok() {
+        return Ok(LinkKind::Hardlink);
+    }
+    fs::copy(src, dst)?;
+    Ok(LinkKind::Copy)
+}
+
+/// Make sure the output directory can be used and create it. It has to be new or
+/// empty, apart from the log and lock of a cancelled run, and must not overlap with
+/// the game directory
+pub fn prepare(source: &Path, output: &Path) -> Result<(), OutputError> {
+    let io_error = |e| OutputError::Io(output.display().to_string(), e);
+
+    if let Ok(entries) = output.read_dir() {
+        let in_use = entries
+            .filter_map(Result::ok)
+            .any(|e| e.file_name() != LOG_FILE_NAME && e.file_name() != INSTALL_LOCK_NAME);
+        if in_use {
+            return Err(OutputError::NotEmpty(output.display().to_string()));
+        }
+    }
+
+    // The output directory may not exist yet, canonicalize what does
+    let mut existing = output;
+    let mut missing = vec![];
+    while !existing.exists() {
+        missing.extend(existing.file_name());
+        existing = existing.parent().unwrap_or(Path::new("."));
+    }
+    let mut canonical_output = existing.canonicalize().map_err(io_error)?;
+    canonical_output.extend(missing.iter().rev());
+
+    let canonical_source = source.canonicalize().map_err(io_error)?;
+    if canonical_output.starts_with(&canonical_source)
+        || canonical_source.starts_with(&canonical_output)
+    {
+        return Err(OutputError::Nested(
+            output.display().to_string(),
+            source.display().to_string(),
+        ));
+    }
+
+    fs::create_dir_all(output).map_err(io_error)
+}
+
+/// Recreate the game directory in `output` with linked files, leaving out the top level
+/// entries named in `skip` such as the update archives or the log file
+pub fn materialize(
+    source: &Path,
+    output: &Path,
+    skip: &[PathBuf],
+) -> Result<LinkReport, OutputError> {
+    let mut report = LinkReport::default();
+    let mut pending = vec![PathBuf::new()];
+
+    while let Some(relative) = pending.pop() {
+        let dir = source.join(&relative);
+        let io_error = |e| OutputError::Io(dir.display().to_string(), e);
+
+        for entry in dir.read_dir().map_err(io_error)? {
+            let entry = entry.map_err(io_error)?;
+            let name = relative.join(entry.file_name());
+            if relative.as_os_str().is_empty() && skip.contains(&name) {
+                continue;
+            }
+
+            let src = entry.path();
+            let dst = output.join(&name);
+            let io_error = |e| OutputError::Io(dst.display().to_string(), e);
+            let file_type = entry.file_type().map_err(io_error)?;
+
+            if file_type.is_dir() {
+                fs::create_dir_all(&dst).map_err(io_error)?;
+                pending.push(name);
+            } else if file_type.is_symlink() {
+                copy_symlink(&src, &dst).map_err(io_error)?;
+                report.copied += 1;
+            } else {
+                match link_file(&src, &dst).map_err(io_error)? {
+                    LinkKind::Reflink => report.reflinked += 1,
+                    LinkKind::Hardlink => report.hardlinked += 1,
+                    LinkKind::Copy => report.copied += 1,
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::copy(src, dst).map(|_| ())
+}
+
+/// Remove the files an archive is about to overwrite. 7z rewrites existing files in
+/// place, which would also change the original install through a hardlink
+pub fn detach(game_dir: &GameDir, entries: &[ArchiveEntry]) -> Result<(), OutputError> {
+    for entry in entries
+        .iter()
+        .filter(|e| !e.is_dir && !MANIFEST_FILES.contains(&e.path.as_str()))
+    {
+        let path = game_dir.resolve(&entry.path)?;
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(OutputError::Io(path.display().to_string(), e)),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    deletefiles::{DeleteFileError, DeleteFiles},
+    hdiffmap::{self, DiffMap, PatchError},
+    paths::{self, GameDir, PathError},
+    seven_util::{ArchiveEntry, SevenError, SevenUtil},
+};
+
+/// Package manifests that are extracted separately and never written to the game directory
+pub const MANIFEST_FILES: [&str; 2] = ["hdiffmap.json", "deletefiles.txt"];
+
+/// Directory inside the game directory archives are extracted to when their paths
+/// differ in case from the install
+pub const STAGING_DIR_NAME: &str = ".hdiff-apply-staging";
+
+#[derive(Debug, Error)]
+pub enum PackageError {
+    #[error("Refusing to extract {archive}: entry '{entry}' {reason}")]
+    UnsafeEntry {
+        archive: String,
+        entry: String,
+        reason: &'static str,
+    },
+    #[error(
+        "{archive} is inconsistent, found {count} conflicts between its manifests and contents"
+    )]
+    Inconsistent { archive: String, count: usize },
+    #[error("Failed to move extracted {0} into place: {1}")]
+    Place(String, #[source] std::io::Error),
+    #[error(transparent)]
+    Path(#[from] PathError),
+    #[error(transparent)]
+    Manifest(#[from] PatchError),
+    #[error(transparent)]
+    DeleteList(#[from] DeleteFileError),
+    #[error(transparent)]
+    Seven(#[from] SevenError),
+}
+
+/// A contradiction between deletefiles.txt, hdiffmap.json and the archive contents
+#[derive(Debug)]
+pub enum Conflict {
+    /// Deleted before the patch that reads it runs
+    DeletedSource(String),
+    /// Deleted although an entry patches it
+    DeletedTarget(String),
+    /// Shipped in the archive and deleted right after extraction
+    DeletedExtracted(String),
+    /// Written by more than one hdiffmap.json entry
+    DuplicateTarget(String, usize),
+    /// Referenced by hdiffmap.json but not in the archive
+    MissingPatch(String),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::DeletedSource(file) => {
+                write!(
+                    f,
+                    "{file} is listed in deletefiles.txt but is a patch source"
+                )
+            }
+            Conflict::DeletedTarget(file) => {
+                write!(
+                    f,
+                    "{file} is listed in deletefiles.txt but is a patch target"
+                )
+            }
+            Conflict::DeletedExtracted(file) => write!(
+                f,
+                "{file} is listed in deletefiles.txt but is extracted from the archive"
+            ),
+            Conflict::DuplicateTarget(file, count) => {
+                write!(f, "{file} is the target of {count} hdiffmap.json entries")
+            }
+            Conflict::MissingPatch(file) => {
+                write!(
+                    f,
+                    "{file} is referenced by hdiffmap.json but not in the archive"
+                )
+            }
+        }
+    }
+}
+
+/// Make sure extracting the archive can't write anything outside of the game directory
+/// and warn about entries that would silently replace files the manifests don't mention
+pub fn check_extraction(
+    game_dir: &GameDir,
+    archive_name: &str,
+    entries: &[ArchiveEntry],
+    referenced_files: &[String],
+) -> Result<(), PackageError> {
+    let unsafe_entry = |entry: &ArchiveEntry, reason| PackageError::UnsafeEntry {
+        archive: archive_name.to_string(),
+        entry: entry.path.clone(),
+        reason,
+    };
+
+    let referenced: HashSet<PathBuf> = referenced_files
+        .iter()
+        .filter_map(|name| paths::normalize(name).ok())
+        .collect();
+
+    for entry in entries {
+        if entry.is_link {
+            return Err(unsafe_entry(entry, "is a link"));
+        }
+
+        if paths::is_rooted(&entry.path) {
+            return Err(unsafe_entry(entry, "has an absolute path"));
+        }
+        if paths::native(&entry.path)
+            .components()
+            .any(|c| matches!(c, std::path::Component::ParentDir))
+        {
+            return Err(unsafe_entry(entry, "contains a parent directory component"));
+        }
+
+        let full_path = game_dir
+            .resolve(&entry.path)
+            .map_err(|_| unsafe_entry(entry, "resolves outside of the game directory"))?;
+
+        if entry.is_dir || MANIFEST_FILES.contains(&entry.path.as_str()) {
+            continue;
+        }
+
+        let normalized = paths::normalize(&entry.path).unwrap_or_default();
+        if full_path.is_file() && !referenced.contains(&normalized) {
+            tracing::warn!(
+                "{} will overwrite {} which isn't referenced by its hdiffmap.json",
+                archive_name,
+                entry.path
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Cross-check the manifests of a package against each other and the archive listing
+pub fn find_conflicts(
+    diff_map: &[DiffMap],
+    deleted_files: &[String],
+    entries: &[ArchiveEntry],
+) -> Vec<Conflict> {
+    // Resolution ignores case, so names differing only in case are the same file
+    let key = paths::folded_name;
+
+    let sources: HashSet<String> = diff_map.iter().map(|e| key(&e.source_file_name)).collect();
+    let archived: HashSet<String> = entries
+        .iter()
+        .filter(|e| !e.is_dir)
+        .map(|e| key(&e.path))
+        .collect();
+
+    let mut targets: HashMap<String, (&str, usize)> = HashMap::new();
+    for entry in diff_map {
+        targets
+            .entry(key(&entry.target_file_name))
+            .or_insert((&entry.target_file_name, 0))
+            .1 += 1;
+    }
+
+    let mut conflicts = vec![];
+
+    for file in deleted_files {
+        let file_key = key(file);
+        if sources.contains(&file_key) {
+            conflicts.push(Conflict::DeletedSource(file.clone()));
+        }
+        if targets.contains_key(&file_key) {
+            conflicts.push(Conflict::DeletedTarget(file.clone()));
+        }
+        if archived.contains(&file_key) {
+            conflicts.push(Conflict::DeletedExtracted(file.clone()));
+        }
+    }
+
+    for (name, count) in targets.values() {
+        if *count > 1 {
+            conflicts.push(Conflict::DuplicateTarget(name.to_string(), *count));
+        }
+    }
+
+    for entry in diff_map {
+        if !archived.contains(&key(&entry.patch_file_name)) {
+            conflicts.push(Conflict::MissingPatch(entry.patch_file_name.clone()));
+        }
+    }
+
+    conflicts
+}
+
+/// Files of the archive whose path exists in the install with a different case
+pub fn has_case_mismatch(game_dir: &GameDir, entries: &[ArchiveEntry]) -> bool {
+    entries.iter().filter(|e| !e.is_dir).any(|entry| {
+        let exact = paths::normalize(&entry.path).map(|p| game_dir.path().join(p));
+        let resolved = game_dir.resolve(&entry.path);
+        matches!((exact, resolved), (Ok(exact), Ok(resolved)) if exact != resolved)
+    })
+}
+
+/// Move the files of an archive extracted to `staging` to where `GameDir::resolve`
+/// places them, so they replace the existing files instead of landing next to them
+pub fn place_extracted(
+    game_dir: &GameDir,
+    staging: &Path,
+    entries: &[ArchiveEntry],
+) -> Result<(), PackageError> {
+    for entry in entries {
+        if entry.is_dir || MANIFEST_FILES.contains(&entry.path.as_str()) {
+            continue;
+        }
+
+        let place_error = |e| PackageError::Place(entry.path.clone(), e);
+        let extracted = staging.join(paths::normalize(&entry.path)?);
+        let target = game_dir.resolve(&entry.path)?;
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(place_error)?;
+        }
+        fs::rename(&extracted, &target).map_err(place_error)?;
+    }
+
+    if let Err(e) = fs::remove_dir_all(staging) {
+        tracing::warn!("Failed to remove {}: {}", staging.display(), e);
+    }
+
+    Ok(())
+}
+
+/// Every file a chain of packages may patch, extract or delete. `packages` holds the
+/// directory with the extracted manifests and the archive of each package
+pub fn touched_files(
+    game_dir: &GameDir,
+    packages: &[(&Path, &Path)],
+) -> Result<BTreeSet<String>, PackageError> {
+    let mut touched = BTreeSet::new();
+
+    for (temp_path, archive_path) in packages {
+        match hdiffmap::load_diff_map(game_dir, &temp_path.join(MANIFEST_FILES[0])) {
+            Ok(diff_map) => touched.extend(
+                diff_map
+                    .into_iter()
+                    .flat_map(|entry| [entry.source_file_name, entry.target_file_name]),
+            ),
+            Err(PatchError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        match DeleteFiles::new(game_dir).listed_files(&temp_path.join(MANIFEST_FILES[1])) {
+            Ok(files) => touched.extend(files),
+            Err(DeleteFileError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        touched.extend(
+            SevenUtil::inst()
+                .list(archive_path)?
+                .into_iter()
+                .filter(|e| !e.is_dir && !MANIFEST_FILES.contains(&e.path.as_str()))
+                .map(|e| e.path),
+        );
+    }
+
+    Ok(touched)
+}
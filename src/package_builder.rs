@@ -0,0 +1,208 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::{
+    out_of_place,
+    package::MANIFEST_FILES,
+    paths::{self, PathError},
+    seven_util::{SevenError, SevenUtil},
+    tools::{Tool, ToolError},
+    utils,
+};
+
+/// Key in the hdiffmap.json of generated packages naming the only client version they
+/// apply to. Official updates don't have it and are chained by their version instead
+pub const BASE_VERSION_KEY: &str = "base_version";
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("Failed to write {0}: {1}")]
+    Io(String, #[source] io::Error),
+    #[error("hdiffz failed on {file} with {status}: {stderr}")]
+    Hdiffz {
+        file: String,
+        status: String,
+        stderr: String,
+    },
+    #[error(transparent)]
+    Path(#[from] PathError),
+    #[error(transparent)]
+    Seven(#[from] SevenError),
+    #[error(transparent)]
+    Tool(#[from] ToolError),
+}
+
+#[derive(Debug)]
+pub struct BuildReport {
+    pub archive: PathBuf,
+    /// Files patched by the package
+    pub diffs: usize,
+    /// Files stored as a whole
+    pub full_files: usize,
+    /// Files listed in deletefiles.txt
+    pub deleted: usize,
+}
+
+/// Read the version a generated package applies to, `None` for official updates
+pub fn base_version(hdiffmap_path: &Path) -> Option<String> {
+    let data = fs::read_to_string(hdiffmap_path).ok()?;
+    let manifest: Value = serde_json::from_str(&data).ok()?;
+    manifest.get(BASE_VERSION_KEY)?.as_str().map(str::to_string)
+}
+
+/// Collects the content of a package in the layout of the update archives: files to
+/// patch come with an `.hdiff` next to them listed in hdiffmap.json, other files are
+/// stored as they are and files to remove are listed in deletefiles.txt
+pub struct PackageBuilder<'a> {
+    hdiffz: &'a Tool,
+    staging: PathBuf,
+    /// `(name, old, new)`, diffed in parallel when the package is written
+    diffs: Vec<(String, PathBuf, PathBuf)>,
+    full_files: usize,
+    deleted: Vec<String>,
+    base_version: Option<String>,
+}
+
+impl<'a> PackageBuilder<'a> {
+    /// Start an empty package staged in `staging`, which is cleared first
+    pub fn new(hdiffz: &'a Tool, staging: &Path) -> Result<Self, BuildError> {
+        let _ = fs::remove_dir_all(staging);
+        fs::create_dir_all(staging).map_err(|e| io_error(staging, e))?;
+
+        Ok(Self {
+            hdiffz,
+            staging: staging.to_path_buf(),
+            diffs: vec![],
+            full_files: 0,
+            deleted: vec![],
+            base_version: None,
+        })
+    }
+
+    /// Only allow the package on this exact client version
+    pub fn base_version(&mut self, version: String) {
+        self.base_version = Some(version);
+    }
+
+    /// Patch `name` from the content of `old` to the content of `new`
+    pub fn diff(&mut self, name: &str, old: &Path, new: &Path) {
+        self.diffs
+            .push((name.to_string(), old.to_path_buf(), new.to_path_buf()));
+    }
+
+    /// Store `source` as `name`
+    pub fn add_file(&mut self, name: &str, source: &Path) -> Result<(), BuildError> {
+        let target = self.staging.join(paths::normalize(name)?);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+        }
+        out_of_place::link_file(source, &target).map_err(|e| io_error(&target, e))?;
+
+        self.full_files += 1;
+        Ok(())
+    }
+
+    /// Remove `name` from the game directory
+    pub fn delete(&mut self, name: &str) {
+        self.deleted.push(name.to_string());
+    }
+
+    /// Run the diffs, write the manifests and pack everything into `archive`
+    pub fn finish(self, archive: &Path) -> Result<BuildReport, BuildError> {
+        let diff_map = self
+            .diffs
+            .par_iter()
+            .map(|(name, old, new)| self.run_hdiffz(name, old, new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut manifest = json!({ "diff_map": diff_map });
+        if let Some(base_version) = &self.base_version {
+            manifest[BASE_VERSION_KEY] = json!(base_version);
+        }
+        let hdiffmap_path = self.staging.join(MANIFEST_FILES[0]);
+        fs::write(&hdiffmap_path, manifest.to_string()).map_err(|e| io_error(&hdiffmap_path, e))?;
+
+        let deletefiles_path = self.staging.join(MANIFEST_FILES[1]);
+        let lines: String = self
+            .deleted
+            .iter()
+            .map(|name| format!("{name}\n"))
+            .collect();
+        fs::write(&deletefiles_path, lines).map_err(|e| io_error(&deletefiles_path, e))?;
+
+        let archive_dir = archive.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(archive_dir).map_err(|e| io_error(archive_dir, e))?;
+        // 7z runs inside the staging directory
+        let archive = archive_dir
+            .canonicalize()
+            .map_err(|e| io_error(archive_dir, e))?
+            .join(archive.file_name().unwrap_or_default());
+        // 7z would add to an existing archive instead of replacing it
+        match fs::remove_file(&archive) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(io_error(&archive, e)),
+            _ => {}
+        }
+        SevenUtil::inst().create_archive(&archive, &self.staging)?;
+
+        let _ = fs::remove_dir_all(&self.staging);
+
+        Ok(BuildReport {
+            archive,
+            diffs: diff_map.len(),
+            full_files: self.full_files,
+            deleted: self.deleted.len(),
+        })
+    }
+
+    /// Diff `old` against `new` and return the hdiffmap.json entry for `name`
+    fn run_hdiffz(&self, name: &str, old: &Path, new: &Path) -> Result<Value, BuildError> {
+        let patch_name = format!("{name}.hdiff");
+        let patch = self.staging.join(paths::normalize(&patch_name)?);
+
+        if let Some(parent) = patch.parent() {
+            fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+        }
+
+        let output = self
+            .hdiffz
+            .command()?
+            .arg("-s")
+            .arg("-f")
+            .arg(old)
+            .arg(new)
+            .arg(&patch)
+            .output()
+            .map_err(|e| io_error(&patch, e))?;
+        utils::log_tool_output("hdiffz", new, &output);
+
+        if !output.status.success() {
+            return Err(BuildError::Hdiffz {
+                file: name.to_string(),
+                status: output.status.to_string(),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+
+        let size = |path: &Path| path.metadata().map(|m| m.len()).unwrap_or(0);
+        tracing::debug!("Created diff for {}", name);
+
+        Ok(json!({
+            "source_file_name": name,
+            "source_file_size": size(old),
+            "target_file_name": name,
+            "target_file_size": size(new),
+            "patch_file_name": patch_name,
+            "patch_file_size": size(&patch),
+        }))
+    }
+}
+
+fn io_error(path: &Path, e: io::Error) -> BuildError {
+    BuildError::Io(path.display().to_string(), e)
+}
@@ -0,0 +1,341 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf, MAIN_SEPARATOR_STR},
+};
+
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Error)]
+pub enum PathError {
+    #[error("Empty path in package manifest")]
+    Empty(),
+    #[error("Refusing to use absolute path from package: {0}")]
+    Absolute(String),
+    #[error("Refusing to use path outside of the game directory: {0}")]
+    Escapes(String),
+    #[error("Refusing to follow symlink out of the game directory: {0} -> {1}")]
+    Symlink(String, String),
+    #[error("{0} matches several files that only differ in case: {1}")]
+    Ambiguous(String, String),
+    #[error("IO error while checking {0}: {1}")]
+    Io(String, #[source] std::io::Error),
+}
+
+/// Manifests and archive listings are written on Windows, so both `\\` and `/`
+/// separate components on every platform
+const SEPARATORS: [char; 2] = ['\\', '/'];
+
+/// Canonical spelling of a manifest path: trimmed and with `/` separators
+pub fn manifest_name(name: &str) -> String {
+    name.trim().replace(SEPARATORS, "/")
+}
+
+/// Spelling of a manifest path that 7z matches against archive entries on this platform
+pub fn archive_entry_name(name: &str) -> String {
+    name.replace(SEPARATORS, MAIN_SEPARATOR_STR)
+}
+
+/// Whether a manifest path starts at a root or drive (`/x`, `\\x`, `C:x`), on any platform
+pub fn is_rooted(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    name.starts_with(SEPARATORS)
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+/// A manifest path with its separators converted for this platform, without any checks
+pub fn native(name: &str) -> PathBuf {
+    name.split(SEPARATORS)
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+/// File name of an archive or other local path for messages
+pub fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+/// Lexically normalize a path taken from a package manifest, rejecting anything
+/// that is absolute or climbs above the directory it is relative to
+pub fn normalize(name: &str) -> Result<PathBuf, PathError> {
+    if is_rooted(name) {
+        return Err(PathError::Absolute(name.to_string()));
+    }
+
+    let mut normalized = PathBuf::new();
+
+    for component in native(name).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(PathError::Escapes(name.to_string()));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(PathError::Absolute(name.to_string()))
+            }
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        return Err(PathError::Empty());
+    }
+
+    Ok(normalized)
+}
+
+/// The game install every manifest path is confined to
+#[derive(Debug, Clone)]
+pub struct GameDir {
+    root: PathBuf,
+    canonical_root: PathBuf,
+}
+
+impl GameDir {
+    pub fn new(root: &Path) -> Result<Self, PathError> {
+        let canonical_root = root
+            .canonicalize()
+            .map_err(|e| PathError::Io(root.display().to_string(), e))?;
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            canonical_root,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Turn a manifest path into a path inside the game directory. Besides the lexical
+    /// checks of `normalize`, the deepest part of the path that already exists is
+    /// canonicalized so symlinked files or directories can't lead outside of the install
+    pub fn resolve(&self, name: &str) -> Result<PathBuf, PathError> {
+        let full_path = self.match_case(name, &normalize(name)?)?;
+
+        let mut existing = full_path.as_path();
+        while existing.symlink_metadata().is_err() {
+            match existing.parent() {
+                Some(parent) => existing = parent,
+                None => return Ok(full_path),
+            }
+        }
+
+        let canonical = existing
+            .canonicalize()
+            .map_err(|e| PathError::Io(existing.display().to_string(), e))?;
+
+        if !canonical.starts_with(&self.canonical_root) {
+            return Err(PathError::Symlink(
+                name.to_string(),
+                canonical.display().to_string(),
+            ));
+        }
+
+        Ok(full_path)
+    }
+
+    /// Installs copied from Windows often differ in case from the manifests. Every
+    /// component that doesn't exist with its exact spelling is replaced by its unique
+    /// case-insensitive match. Components without a match are kept, they may be new files
+    fn match_case(&self, name: &str, relative: &Path) -> Result<PathBuf, PathError> {
+        let exact = self.root.join(relative);
+        if exact.symlink_metadata().is_ok() {
+            return Ok(exact);
+        }
+
+        let mut resolved = self.root.clone();
+        let mut components = relative.components();
+
+        while let Some(component) = components.next() {
+            let candidate = resolved.join(component);
+            if candidate.symlink_metadata().is_ok() {
+                resolved = candidate;
+                continue;
+            }
+
+            match find_folded(&resolved, component.as_os_str(), name)? {
+                Some(matched) => resolved.push(matched),
+                None => {
+                    resolved.push(component);
+                    resolved.extend(components);
+                    break;
+                }
+            }
+        }
+
+        if resolved != exact {
+            tracing::debug!("Resolved {} to {}", name, resolved.display());
+        }
+        Ok(resolved)
+    }
+}
+
+/// Key under which manifest paths that `GameDir::resolve` may map to the same file
+/// compare equal
+pub fn folded_name(name: &str) -> String {
+    let normalized = normalize(name)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| name.to_string());
+    normalized.to_lowercase().nfc().collect()
+}
+
+/// Case and Unicode normalization form independent spelling of a file name
+fn fold(name: &OsStr) -> Option<String> {
+    name.to_str()
+        .map(|name| name.to_lowercase().nfc().collect())
+}
+
+/// The only entry of `dir` whose folded name equals the folded `component`
+fn find_folded(dir: &Path, component: &OsStr, name: &str) -> Result<Option<OsString>, PathError> {
+    let Some(wanted) = fold(component) else {
+        return Ok(None);
+    };
+    let Ok(entries) = dir.read_dir() else {
+        return Ok(None);
+    };
+
+    let mut matches: Vec<OsString> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name())
+        .filter(|entry_name| fold(entry_name).as_ref() == Some(&wanted))
+        .collect();
+
+    match matches.len() {
+        0 => Ok(None),
+        1 => Ok(matches.pop()),
+        _ => {
+            let names: Vec<_> = matches.iter().map(|m| m.to_string_lossy()).collect();
+            Err(PathError::Ambiguous(name.to_string(), names.join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Fresh directory with a `game` install and an `outside` directory next to it
+    fn fixture(name: &str) -> (PathBuf, GameDir) {
+        let root =
+            std::env::temp_dir().join(format!("hdiff-apply-paths-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("game/Data")).unwrap();
+        fs::create_dir_all(root.join("outside")).unwrap();
+        fs::write(root.join("game/Data/File.TXT"), "").unwrap();
+        fs::write(root.join("outside/secret.txt"), "").unwrap();
+
+        let game_dir = GameDir::new(&root.join("game")).unwrap();
+        (root, game_dir)
+    }
+
+    #[test]
+    fn parent_components_stay_inside() {
+        assert_eq!(normalize("a/./b/../c.txt").unwrap(), native("a/c.txt"));
+        assert_eq!(normalize(r"a\b\..\c.txt").unwrap(), native("a/c.txt"));
+        assert!(matches!(normalize("../c.txt"), Err(PathError::Escapes(_))));
+        assert!(matches!(
+            normalize(r"a\..\..\c.txt"),
+            Err(PathError::Escapes(_))
+        ));
+        assert!(matches!(normalize("a/.."), Err(PathError::Empty())));
+        assert!(matches!(normalize("./"), Err(PathError::Empty())));
+    }
+
+    #[test]
+    fn rooted_paths_are_rejected() {
+        for name in [
+            "/etc/passwd",
+            r"\Windows\win.ini",
+            r"\\server\share\x",
+            "C:x",
+            r"c:\x",
+        ] {
+            assert!(
+                matches!(normalize(name), Err(PathError::Absolute(_))),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_matches_case_of_existing_files() {
+        let (root, game_dir) = fixture("case");
+
+        assert_eq!(
+            game_dir.resolve(r"data\file.txt").unwrap(),
+            root.join("game/Data/File.TXT")
+        );
+        // New files keep their spelling below the deepest match
+        assert_eq!(
+            game_dir.resolve("DATA/New/x.txt").unwrap(),
+            root.join("game/Data/New/x.txt")
+        );
+        assert!(matches!(
+            game_dir.resolve("Data/../../outside/secret.txt"),
+            Err(PathError::Escapes(_))
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resolve_refuses_ambiguous_case_folded_matches() {
+        let (root, game_dir) = fixture("ambiguous");
+        fs::write(root.join("game/Data/file.txt"), "").unwrap();
+
+        // An exact match is never ambiguous
+        assert_eq!(
+            game_dir.resolve("Data/file.txt").unwrap(),
+            root.join("game/Data/file.txt")
+        );
+        assert!(matches!(
+            game_dir.resolve("data/FILE.txt"),
+            Err(PathError::Ambiguous(..))
+        ));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_refuses_symlinks_out_of_the_game_dir() {
+        use std::os::unix::fs::symlink;
+
+        let (root, game_dir) = fixture("symlink");
+        symlink(root.join("outside"), root.join("game/Linked")).unwrap();
+        symlink(
+            root.join("outside/secret.txt"),
+            root.join("game/secret.txt"),
+        )
+        .unwrap();
+        symlink(root.join("game/Data"), root.join("game/Alias")).unwrap();
+
+        // The deepest existing ancestor is the symlinked directory
+        assert!(matches!(
+            game_dir.resolve("Linked/new/file.txt"),
+            Err(PathError::Symlink(..))
+        ));
+        assert!(matches!(
+            game_dir.resolve("linked/secret.txt"),
+            Err(PathError::Symlink(..))
+        ));
+        assert!(matches!(
+            game_dir.resolve("secret.txt"),
+            Err(PathError::Symlink(..))
+        ));
+        assert_eq!(
+            game_dir.resolve("Alias/File.TXT").unwrap(),
+            root.join("game/Alias/File.TXT")
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
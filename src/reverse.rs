@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::{
+    backup::{Backup, BackupError},
+    binary_version::{BinaryVersion, BINARY_VERSION_PATH},
+    package_builder::{BuildError, BuildReport, PackageBuilder},
+    paths::{GameDir, PathError},
+    tools::Tool,
+};
+
+#[derive(Debug, Error)]
+pub enum ReverseError {
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    Path(#[from] PathError),
+}
+
+/// Builds a package taking the game directory back to the state saved in the backup:
+/// patched files are diffed back, deleted or replaced ones are stored as they were and
+/// files the update added are deleted
+pub struct ReversePackage<'a> {
+    game_dir: &'a GameDir,
+    backup: &'a Backup,
+    hdiffz: &'a Tool,
+}
+
+impl<'a> ReversePackage<'a> {
+    pub fn new(game_dir: &'a GameDir, backup: &'a Backup, hdiffz: &'a Tool) -> Self {
+        Self {
+            game_dir,
+            backup,
+            hdiffz,
+        }
+    }
+
+    /// Write `reverse_<from>_to_<to>.7z` into `output_dir`, staging its content in `work_dir`
+    pub fn build(
+        &self,
+        from: &BinaryVersion,
+        to: &BinaryVersion,
+        work_dir: &Path,
+        output_dir: &Path,
+    ) -> Result<BuildReport, ReverseError> {
+        let mut builder = PackageBuilder::new(self.hdiffz, &work_dir.join("reverse"))?;
+        builder.base_version(from.to_string());
+
+        for name in self.backup.saved_files()? {
+            let current = self.game_dir.resolve(&name)?;
+            let saved = self.backup.backup_file(&name)?;
+
+            // The version file is read from the archive before anything is patched
+            if name != BINARY_VERSION_PATH && current.is_file() {
+                builder.diff(&name, &current, &saved);
+            } else {
+                builder.add_file(&name, &saved)?;
+            }
+        }
+
+        for name in self.backup.created_files() {
+            if self.game_dir.resolve(&name)?.symlink_metadata().is_ok() {
+                builder.delete(&name);
+            }
+        }
+
+        Ok(builder.finish(&output_dir.join(format!("reverse_{from}_to_{to}.7z")))?)
+    }
+}
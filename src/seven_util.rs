@@ -1,9 +1,13 @@
 // I didnt find any good 7z crates so this will have to do for now
 
-use std::{path::PathBuf, process::Command, sync::OnceLock};
+use std::{path::Path, sync::OnceLock};
 
+use crate::{
+    paths,
+    tools::{Tool, ToolError},
+    utils,
+};
 use thiserror::Error;
-use crate::{utils, TEMP_DIR_NAME};
 
 static INST: OnceLock<SevenUtil> = OnceLock::new();
 
@@ -13,62 +17,118 @@ pub enum SevenError {
     CommandError(#[source] std::io::Error),
     #[error("7-zip extraction failed: '{0}'")]
     ExtractionFailed(String),
-    #[error("Embedded 7z.exe extraction failed: {0}")]
-    EmbeddedExtractionFailed(String),
+    #[error("Integrity test of {archive} failed at entry '{entry}': {reason}")]
+    CorruptEntry {
+        archive: String,
+        entry: String,
+        reason: String,
+    },
+    #[error("Failed to create {0}: '{1}'")]
+    ArchiveFailed(String, String),
+    #[error("Integrity test of {0} failed: '{1}'")]
+    TestFailed(String, String),
+    #[error(transparent)]
+    Tool(#[from] ToolError),
+}
+
+/// One file or directory inside an archive as reported by `7z l -slt`
+#[derive(Debug, Default, Clone)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64,
+    pub is_dir: bool,
+    pub is_link: bool,
+}
+
+impl ArchiveEntry {
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "Path" => self.path = value.to_string(),
+            "Size" => self.size = value.parse().unwrap_or(0),
+            "Folder" => self.is_dir = value == "+",
+            // Unix mode strings show up in the attributes, e.g. "A_ -lrwxrwxrwx"
+            "Attributes" => {
+                self.is_dir |= value.starts_with('D');
+                self.is_link |= value
+                    .split_whitespace()
+                    .any(|part| part.trim_start_matches('-').starts_with('l') && part.len() >= 10);
+            }
+            "Symbolic Link" | "Hard Link" | "Link" => self.is_link |= !value.is_empty(),
+            _ => {}
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct SevenUtil {
-    executable: PathBuf,
+    tool: Tool,
 }
 
 impl SevenUtil {
-    pub fn new() -> Result<Self, SevenError> {
-        let executable = Self::extract_embedded_sevenz()?;
-        Ok(Self { executable })
+    pub fn new(tool: Tool) -> Self {
+        Self { tool }
     }
 
-    /// Extract the embedded 7z.exe to the temp directory and return its path
-    fn extract_embedded_sevenz() -> Result<PathBuf, SevenError> {
-        // 7z.exe is embedded via include_bytes!
-        const SEVENZ_BIN: &[u8] = include_bytes!("../bin/7z.exe");
-        let temp_dir = std::env::temp_dir().join(TEMP_DIR_NAME);
-        std::fs::create_dir_all(&temp_dir).map_err(|e| {
-            SevenError::EmbeddedExtractionFailed(format!("Failed to create temp dir: {e}"))
-        })?;
-        let exe_path = temp_dir.join("7z.exe");
-        // Overwrite if already exists
-        std::fs::write(&exe_path, SEVENZ_BIN).map_err(|e| {
-            SevenError::EmbeddedExtractionFailed(format!("Failed to write 7z.exe: {e}"))
-        })?;
-        Ok(exe_path)
+    /// Set up the shared instance, must be called before `inst`
+    pub fn init(tool: Tool) -> &'static SevenUtil {
+        INST.get_or_init(|| SevenUtil::new(tool))
     }
 
     pub fn inst() -> &'static SevenUtil {
-        INST.get_or_init(|| match SevenUtil::new() {
-            Ok(s) => s,
-            Err(e) => {
-                tracing::error!("{}", e);
-                utils::wait_for_input();
-                std::process::exit(0);
-            }
-        })
+        INST.get().expect("SevenUtil::init was not called")
     }
 
     pub fn extract_specific_files_to(
         &self,
-        archive: &PathBuf,
+        archive: &Path,
         files_in_archive: &[&str],
-        dst: &PathBuf,
+        dst: &Path,
     ) -> Result<(), SevenError> {
-        let output = Command::new(&self.executable)
+        let output = self
+            .tool
+            .command()?
             .arg("e")
             .arg(archive)
-            .args(files_in_archive)
-            .arg(format!("-o{}", dst.display().to_string()))
+            .args(
+                files_in_archive
+                    .iter()
+                    .map(|f| paths::archive_entry_name(f)),
+            )
+            .arg(format!("-o{}", dst.display()))
+            .arg("-aoa")
+            .output()
+            .map_err(SevenError::CommandError)?;
+        utils::log_tool_output("7z", archive, &output);
+
+        if !output.status.success() {
+            let stderr_msg = String::from_utf8_lossy(&output.stderr);
+            return Err(SevenError::ExtractionFailed(stderr_msg.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Extract single entries keeping their directory structure
+    pub fn extract_paths_to(
+        &self,
+        archive: &Path,
+        files_in_archive: &[&str],
+        dst: &Path,
+    ) -> Result<(), SevenError> {
+        let output = self
+            .tool
+            .command()?
+            .arg("x")
+            .arg(archive)
+            .args(
+                files_in_archive
+                    .iter()
+                    .map(|f| paths::archive_entry_name(f)),
+            )
+            .arg(format!("-o{}", dst.display()))
             .arg("-aoa")
             .output()
             .map_err(SevenError::CommandError)?;
+        utils::log_tool_output("7z", archive, &output);
 
         if !output.status.success() {
             let stderr_msg = String::from_utf8_lossy(&output.stderr);
@@ -78,15 +138,18 @@ impl SevenUtil {
         Ok(())
     }
 
-    pub fn extract_hdiff_to(&self, archive: &PathBuf, dst: &PathBuf) -> Result<(), SevenError> {
-        let output = Command::new(&self.executable)
+    pub fn extract_hdiff_to(&self, archive: &Path, dst: &Path) -> Result<(), SevenError> {
+        let output = self
+            .tool
+            .command()?
             .arg("x")
             .arg(archive)
-            .arg(format!("-o{}", dst.display().to_string()))
+            .arg(format!("-o{}", dst.display()))
             .arg("-aoa")
             .args(["-x!hdiffmap.json", "-x!deletefiles.txt"])
             .output()
             .map_err(SevenError::CommandError)?;
+        utils::log_tool_output("7z", archive, &output);
 
         if !output.status.success() {
             let stderr_msg = String::from_utf8_lossy(&output.stderr);
@@ -95,4 +158,111 @@ impl SevenUtil {
 
         Ok(())
     }
+
+    /// Pack everything inside `dir` into a new 7z archive, paths relative to `dir`
+    pub fn create_archive(&self, archive: &Path, dir: &Path) -> Result<(), SevenError> {
+        let output = self
+            .tool
+            .command()?
+            .current_dir(dir)
+            .arg("a")
+            .arg("-t7z")
+            .arg(archive)
+            .arg("*")
+            .output()
+            .map_err(SevenError::CommandError)?;
+        utils::log_tool_output("7z", archive, &output);
+
+        if !output.status.success() {
+            let stderr_msg = String::from_utf8_lossy(&output.stderr);
+            return Err(SevenError::ArchiveFailed(
+                archive.display().to_string(),
+                stderr_msg.to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Run `7z t` on the archive which checks the CRC of every entry without writing anything
+    pub fn test_archive(&self, archive: &Path) -> Result<(), SevenError> {
+        let output = self
+            .tool
+            .command()?
+            .arg("t")
+            .arg(archive)
+            .output()
+            .map_err(SevenError::CommandError)?;
+        utils::log_tool_output("7z", archive, &output);
+
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let archive_name = archive.display().to_string();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        // 7z reports broken entries as "ERROR: CRC Failed : path/to/entry"
+        for line in stdout.lines().chain(stderr.lines()) {
+            let Some(message) = line.trim().strip_prefix("ERROR:") else {
+                continue;
+            };
+
+            if let Some((reason, entry)) = message.rsplit_once(" : ") {
+                return Err(SevenError::CorruptEntry {
+                    archive: archive_name,
+                    entry: entry.trim().to_string(),
+                    reason: reason.trim().to_string(),
+                });
+            }
+        }
+
+        let detail = if stderr.trim().is_empty() {
+            stdout.trim().lines().last().unwrap_or_default().to_string()
+        } else {
+            stderr.trim().to_string()
+        };
+        Err(SevenError::TestFailed(archive_name, detail))
+    }
+
+    /// List every entry of the archive without extracting anything
+    pub fn list(&self, archive: &Path) -> Result<Vec<ArchiveEntry>, SevenError> {
+        let output = self
+            .tool
+            .command()?
+            .arg("l")
+            .arg("-slt")
+            .arg(archive)
+            .output()
+            .map_err(SevenError::CommandError)?;
+
+        if !output.status.success() {
+            let stderr_msg = String::from_utf8_lossy(&output.stderr);
+            return Err(SevenError::ExtractionFailed(stderr_msg.to_string()));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut entries = vec![];
+        let mut current: Option<ArchiveEntry> = None;
+
+        // Archive properties come first, the entries start after the "----------" line
+        // and are separated by blank lines
+        for line in stdout
+            .lines()
+            .skip_while(|l| !l.starts_with("----------"))
+            .skip(1)
+        {
+            match line.split_once(" = ") {
+                Some((key, value)) => current
+                    .get_or_insert_with(ArchiveEntry::default)
+                    .set(key.trim(), value.trim()),
+                None if line.trim().is_empty() => entries.extend(current.take()),
+                None => {}
+            }
+        }
+        entries.extend(current.take());
+
+        Ok(entries)
+    }
 }
@@ -0,0 +1,178 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs::{File, Metadata},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    binary_version::{BinaryVersion, BINARY_VERSION_PATH},
+    package_builder::{BuildError, BuildReport, PackageBuilder},
+    paths::{GameDir, PathError},
+    tools::Tool,
+};
+
+#[derive(Debug, Error)]
+pub enum SquashError {
+    #[error("Failed to read {0}: {1}")]
+    Io(String, #[source] io::Error),
+    #[error("{0} changes between file and directory, which a single package can't express")]
+    TypeChanged(String),
+    #[error("The squashed package doesn't give the same result as applying the packages one by one:\n{0}")]
+    Mismatch(String),
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    Path(#[from] PathError),
+}
+
+fn metadata(path: &Path) -> Option<Metadata> {
+    path.symlink_metadata().ok()
+}
+
+/// Names of the entries inside `name` in either of the two directories
+fn children(name: &str, dirs: [&Path; 2]) -> BTreeSet<String> {
+    dirs.iter()
+        .filter_map(|dir| dir.read_dir().ok())
+        .flat_map(|entries| entries.filter_map(Result::ok))
+        .map(|entry| format!("{}/{}", name, entry.file_name().to_string_lossy()))
+        .collect()
+}
+
+/// Compare two files byte by byte
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut a = BufReader::with_capacity(1 << 20, File::open(a)?);
+    let mut b = BufReader::with_capacity(1 << 20, File::open(b)?);
+    let mut buf_a = vec![0; 1 << 16];
+    let mut buf_b = vec![0; 1 << 16];
+
+    loop {
+        let n = a.read(&mut buf_a)?;
+        if n == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Turns the difference between a base install and the result of applying a chain of
+/// packages to a copy of it into one package. Only the files the chain touched are
+/// looked at, everything else is known to be unchanged
+pub struct Squash<'a> {
+    base: &'a GameDir,
+    result: &'a GameDir,
+    /// Resolved result paths already in the package, names of different packages may
+    /// differ in case
+    seen: HashSet<PathBuf>,
+}
+
+impl<'a> Squash<'a> {
+    pub fn new(base: &'a GameDir, result: &'a GameDir) -> Self {
+        Self {
+            base,
+            result,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Write the package for clients on `base_version` to `archive`, staging its content
+    /// in `staging`
+    pub fn build(
+        mut self,
+        touched: &BTreeSet<String>,
+        base_version: &BinaryVersion,
+        hdiffz: &Tool,
+        staging: &Path,
+        archive: &Path,
+    ) -> Result<BuildReport, SquashError> {
+        let mut builder = PackageBuilder::new(hdiffz, staging)?;
+        // It skips versions, so the version chain wouldn't accept it otherwise
+        builder.base_version(base_version.to_string());
+        for name in touched {
+            self.add(name, &mut builder)?;
+        }
+        Ok(builder.finish(archive)?)
+    }
+
+    fn add(&mut self, name: &str, builder: &mut PackageBuilder) -> Result<(), SquashError> {
+        let old = self.base.resolve(name)?;
+        let new = self.result.resolve(name)?;
+        if !self.seen.insert(new.clone()) {
+            return Ok(());
+        }
+
+        match (metadata(&old), metadata(&new)) {
+            (None, None) => {}
+            (Some(_), None) => builder.delete(name),
+            (old_meta, Some(new_meta)) if new_meta.is_dir() => {
+                if old_meta.is_some_and(|m| !m.is_dir()) {
+                    return Err(SquashError::TypeChanged(name.to_string()));
+                }
+                for child in children(name, [&old, &new]) {
+                    self.add(&child, builder)?;
+                }
+            }
+            (None, Some(_)) => builder.add_file(name, &new)?,
+            (Some(old_meta), Some(_)) if old_meta.is_dir() => {
+                return Err(SquashError::TypeChanged(name.to_string()));
+            }
+            (Some(_), Some(_)) => {
+                let unchanged = same_contents(&old, &new)
+                    .map_err(|e| SquashError::Io(new.display().to_string(), e))?;
+
+                // The version file is read from the archive before anything is patched
+                if name == BINARY_VERSION_PATH {
+                    builder.add_file(name, &new)?;
+                } else if !unchanged {
+                    builder.diff(name, &old, &new);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Names below `touched` whose content differs between the two directories
+pub fn compare(
+    expected: &GameDir,
+    actual: &GameDir,
+    touched: &BTreeSet<String>,
+) -> Result<Vec<String>, SquashError> {
+    let mut pending: Vec<String> = touched.iter().cloned().collect();
+    let mut mismatches = vec![];
+
+    while let Some(name) = pending.pop() {
+        let a = expected.resolve(&name)?;
+        let b = actual.resolve(&name)?;
+
+        let same = match (metadata(&a), metadata(&b)) {
+            (None, None) => true,
+            (Some(a_meta), Some(b_meta)) if a_meta.is_dir() && b_meta.is_dir() => {
+                pending.extend(children(&name, [&a, &b]));
+                true
+            }
+            (Some(a_meta), Some(b_meta)) if a_meta.is_file() && b_meta.is_file() => {
+                same_contents(&a, &b).map_err(|e| SquashError::Io(b.display().to_string(), e))?
+            }
+            _ => false,
+        };
+
+        if !same {
+            mismatches.push(name);
+        }
+    }
+
+    mismatches.sort();
+    mismatches.dedup();
+    Ok(mismatches)
+}
@@ -0,0 +1,415 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::checksum::{self, Algorithm, ChecksumError};
+
+#[derive(Debug, Error)]
+pub enum ToolError {
+    #[error("Failed to set up {0}: {1}")]
+    Io(String, #[source] std::io::Error),
+    #[error("Refusing to use {0}: it is not private to the current user")]
+    InsecureDir(String),
+    #[error("No private directory for the helper tools, {0} is not set")]
+    NoPrivateDir(&'static str),
+    #[error("{0} changed on disk since it was written, refusing to run it")]
+    Tampered(String),
+    #[error("No usable {0} found, install it or pass its location with --{1}")]
+    NotFound(&'static str, &'static str),
+    #[error("Failed to read {0}: {1}")]
+    Read(String, #[source] ChecksumError),
+    #[error("Failed to run {0}: {1}")]
+    Probe(String, #[source] std::io::Error),
+    #[error("{path} is {name} {found}, at least {required} is required")]
+    Incompatible {
+        path: String,
+        name: &'static str,
+        found: String,
+        required: String,
+    },
+    #[error("Could not determine the version of {0}, is it really {1}?")]
+    UnknownVersion(String, &'static str),
+}
+
+/// A helper binary compiled into hdiff-apply
+pub struct Embedded {
+    pub name: &'static str,
+    pub bytes: &'static [u8],
+}
+
+impl Embedded {
+    /// Windows builds run the bundled .exe directly, elsewhere only native binaries
+    /// can run without Wine
+    fn runs_natively(&self) -> bool {
+        cfg!(target_os = "windows") || self.bytes.starts_with(b"\x7fELF")
+    }
+}
+
+#[cfg(feature = "embedded-tools")]
+const SEVENZ: Embedded = Embedded {
+    name: "7z.exe",
+    bytes: include_bytes!("../bin/7z.exe"),
+};
+
+#[cfg(feature = "embedded-tools")]
+const HPATCHZ: Embedded = Embedded {
+    name: "hpatchz.exe",
+    bytes: include_bytes!("../bin/hpatchz.exe"),
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ToolKind {
+    SevenZip,
+    Hpatchz,
+    /// Only needed to build reverse packages, never bundled
+    Hdiffz,
+}
+
+impl ToolKind {
+    fn display_name(self) -> &'static str {
+        match self {
+            ToolKind::SevenZip => "7-Zip",
+            ToolKind::Hpatchz => "hpatchz",
+            ToolKind::Hdiffz => "hdiffz",
+        }
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            ToolKind::SevenZip => "sevenzip-path",
+            ToolKind::Hpatchz => "hpatchz-path",
+            ToolKind::Hdiffz => "hdiffz-path",
+        }
+    }
+
+    /// Executable names looked up in PATH
+    fn path_names(self) -> &'static [&'static str] {
+        match self {
+            ToolKind::SevenZip => &["7z", "7zz"],
+            ToolKind::Hpatchz => &["hpatchz"],
+            ToolKind::Hdiffz => &["hdiffz"],
+        }
+    }
+
+    fn embedded(self) -> Option<&'static Embedded> {
+        #[cfg(feature = "embedded-tools")]
+        return match self {
+            ToolKind::SevenZip => Some(&SEVENZ),
+            ToolKind::Hpatchz => Some(&HPATCHZ),
+            ToolKind::Hdiffz => None,
+        };
+
+        #[cfg(not(feature = "embedded-tools"))]
+        None
+    }
+
+    /// Oldest release known to work. 7-Zip 9.20 is the first with `l -slt` output in the
+    /// format we parse, hpatchz 4.0 the first that applies single compressed diffs and
+    /// hdiffz 4.0 the first that creates them
+    fn min_version(self) -> (u32, u32) {
+        match self {
+            ToolKind::SevenZip => (9, 20),
+            ToolKind::Hpatchz | ToolKind::Hdiffz => (4, 0),
+        }
+    }
+
+    /// The tools print their version in the banner when run without arguments, e.g.
+    /// `7-Zip (z) 23.01 (x64) : ...`, `p7zip Version 16.02 ...` or `HDiffPatch::hpatchz v4.6.9`
+    fn parse_version(self, output: &str) -> Option<((u32, u32), String)> {
+        let markers: &[&str] = match self {
+            ToolKind::SevenZip => &["7-Zip", "p7zip"],
+            ToolKind::Hpatchz => &["hpatchz"],
+            ToolKind::Hdiffz => &["hdiffz"],
+        };
+
+        output
+            .lines()
+            .filter(|line| markers.iter().any(|marker| line.contains(marker)))
+            .flat_map(str::split_whitespace)
+            .find_map(|token| {
+                let text = token.trim_start_matches('v');
+                let mut parts = text.split('.');
+                let major = parts.next()?.parse().ok()?;
+                let minor = parts.next()?.parse().ok()?;
+                Some(((major, minor), text.to_string()))
+            })
+    }
+
+    /// Run the tool and make sure it is new enough, returns the version it reports
+    fn probe(self, path: &Path) -> Result<String, ToolError> {
+        let output = Command::new(path)
+            .output()
+            .map_err(|e| ToolError::Probe(path.display().to_string(), e))?;
+        let text = format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let (version, version_text) = self.parse_version(&text).ok_or_else(|| {
+            ToolError::UnknownVersion(path.display().to_string(), self.display_name())
+        })?;
+
+        let required = self.min_version();
+        if version < required {
+            return Err(ToolError::Incompatible {
+                path: path.display().to_string(),
+                name: self.display_name(),
+                found: version_text,
+                required: format!("{}.{}", required.0, required.1),
+            });
+        }
+
+        Ok(version_text)
+    }
+
+    fn find_in_path(self) -> Vec<PathBuf> {
+        let Some(path_var) = env::var_os("PATH") else {
+            return vec![];
+        };
+
+        env::split_paths(&path_var)
+            .flat_map(|dir| {
+                self.path_names()
+                    .iter()
+                    .map(move |name| dir.join(format!("{name}{}", env::consts::EXE_SUFFIX)))
+            })
+            .filter(|candidate| candidate.is_file())
+            .collect()
+    }
+}
+
+/// Pick the helper to use: an explicitly configured path, the embedded copy if it can
+/// run on this system, a compatible one from PATH and finally the embedded copy anyway,
+/// which works on Linux when Wine is registered for .exe files
+pub fn locate(
+    kind: ToolKind,
+    explicit: Option<&Path>,
+    tools_dir: &Path,
+) -> Result<Tool, ToolError> {
+    if let Some(path) = explicit {
+        let version = kind.probe(path)?;
+        tracing::debug!(
+            "Using {} {} at {}",
+            kind.display_name(),
+            version,
+            path.display()
+        );
+        return Tool::external(path);
+    }
+
+    if let Some(embedded) = kind.embedded().filter(|e| e.runs_natively()) {
+        return Tool::install(embedded, tools_dir);
+    }
+
+    for candidate in kind.find_in_path() {
+        match kind.probe(&candidate) {
+            Ok(version) => {
+                tracing::debug!(
+                    "Using {} {} at {}",
+                    kind.display_name(),
+                    version,
+                    candidate.display()
+                );
+                return Tool::external(&candidate);
+            }
+            Err(e) => tracing::warn!("Skipping {}: {}", candidate.display(), e),
+        }
+    }
+
+    match kind.embedded() {
+        Some(embedded) => Tool::install(embedded, tools_dir),
+        None => Err(ToolError::NotFound(kind.display_name(), kind.flag())),
+    }
+}
+
+/// Per-user directory the helpers are kept in between runs, `%LOCALAPPDATA%\hdiff-apply\tools`
+/// on Windows and `$XDG_CACHE_HOME/hdiff-apply/tools` elsewhere. There is no fallback to
+/// a shared temporary directory, where others could swap a helper between its hash
+/// check and its execution
+pub fn private_dir() -> Result<PathBuf, ToolError> {
+    private_subdir("tools")
+}
+
+/// Per-user directory for the locks named after the installs they guard, next to the helpers
+pub fn lock_dir() -> Result<PathBuf, ToolError> {
+    private_subdir("locks")
+}
+
+fn private_subdir(name: &str) -> Result<PathBuf, ToolError> {
+    let base = if cfg!(target_os = "windows") {
+        env::var_os("LOCALAPPDATA")
+            .map(PathBuf::from)
+            .ok_or(ToolError::NoPrivateDir("LOCALAPPDATA"))?
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .ok_or(ToolError::NoPrivateDir("HOME"))?
+    };
+
+    let dir = base.join(env!("CARGO_PKG_NAME")).join(name);
+
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent).map_err(|e| ToolError::Io(parent.display().to_string(), e))?;
+    }
+    platform::create_private_dir(&dir)?;
+
+    Ok(dir)
+}
+
+/// Create `dir` so that only the current user can use it, or make sure an existing one is
+/// a real directory only they can write to
+pub fn create_private_dir(dir: &Path) -> Result<(), ToolError> {
+    platform::create_private_dir(dir)
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// A helper binary ready to be executed
+pub struct Tool {
+    path: PathBuf,
+    sha256: String,
+}
+
+impl Tool {
+    /// Use a helper installed on the system. Its hash is taken now so the binary that was
+    /// probed is the one that runs
+    pub fn external(path: &Path) -> Result<Self, ToolError> {
+        let sha256 = checksum::hash_file(path, Algorithm::Sha256)
+            .map_err(|e| ToolError::Read(path.display().to_string(), e))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            sha256,
+        })
+    }
+
+    /// Write the helper into `dir` unless an identical copy is already there
+    pub fn install(embedded: &Embedded, dir: &Path) -> Result<Self, ToolError> {
+        let path = dir.join(embedded.name);
+        let expected = sha256(embedded.bytes);
+        let io_error = |e| ToolError::Io(path.display().to_string(), e);
+
+        let up_to_date =
+            checksum::hash_file(&path, Algorithm::Sha256).is_ok_and(|actual| actual == expected);
+
+        if up_to_date {
+            tracing::debug!("{} is up to date", path.display());
+        } else {
+            // Write under a temporary name so a half written helper is never executed
+            let temp_path = dir.join(format!(".{}.tmp", embedded.name));
+            let _ = fs::remove_file(&temp_path);
+
+            let mut file = platform::create_executable(&temp_path).map_err(io_error)?;
+            file.write_all(embedded.bytes)
+                .and_then(|_| file.sync_all())
+                .map_err(io_error)?;
+            drop(file);
+            fs::rename(&temp_path, &path).map_err(io_error)?;
+
+            tracing::debug!("Wrote {}", path.display());
+        }
+
+        Ok(Self {
+            path,
+            sha256: expected,
+        })
+    }
+
+    /// Build a command running the helper. The binary is hashed again on every call, so
+    /// callers spawn the command right away instead of keeping it around
+    pub fn command(&self) -> Result<Command, ToolError> {
+        let actual = checksum::hash_file(&self.path, Algorithm::Sha256)
+            .map_err(|_| ToolError::Tampered(self.path.display().to_string()))?;
+        if actual != self.sha256 {
+            return Err(ToolError::Tampered(self.path.display().to_string()));
+        }
+
+        Ok(Command::new(&self.path))
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::{
+        fs::{self, DirBuilder, File, OpenOptions, Permissions},
+        os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        path::Path,
+    };
+
+    use super::ToolError;
+
+    pub fn user_id() -> u32 {
+        // SAFETY: getuid has no preconditions and can't fail
+        unsafe { libc::getuid() }
+    }
+
+    /// Create `dir` as 0700, or make sure an existing one is a real directory that
+    /// belongs to the current user and can't be written by anyone else
+    pub fn create_private_dir(dir: &Path) -> Result<(), ToolError> {
+        let display = dir.display().to_string();
+
+        match DirBuilder::new().mode(0o700).create(dir) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(ToolError::Io(display, e)),
+        }
+
+        let metadata = fs::symlink_metadata(dir).map_err(|e| ToolError::Io(display.clone(), e))?;
+        if !metadata.is_dir() || metadata.uid() != user_id() {
+            return Err(ToolError::InsecureDir(display));
+        }
+        if metadata.mode() & 0o077 != 0 {
+            fs::set_permissions(dir, Permissions::from_mode(0o700))
+                .map_err(|e| ToolError::Io(display, e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn create_executable(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o700)
+            .open(path)
+    }
+}
+
+#[cfg(not(unix))]
+mod platform {
+    use std::{
+        fs::{File, OpenOptions},
+        path::Path,
+    };
+
+    use super::ToolError;
+
+    /// Directories under %LOCALAPPDATA% inherit an ACL that only grants the owner access.
+    /// A junction or symlink could point anywhere, so only a real directory is accepted
+    pub fn create_private_dir(dir: &Path) -> Result<(), ToolError> {
+        let display = dir.display().to_string();
+        std::fs::create_dir_all(dir).map_err(|e| ToolError::Io(display.clone(), e))?;
+
+        let metadata =
+            std::fs::symlink_metadata(dir).map_err(|e| ToolError::Io(display.clone(), e))?;
+        if !metadata.is_dir() {
+            return Err(ToolError::InsecureDir(display));
+        }
+
+        Ok(())
+    }
+
+    pub fn create_executable(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new().write(true).create_new(true).open(path)
+    }
+}
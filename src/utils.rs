@@ -1,22 +1,95 @@
 use std::{
-    env::{current_dir, temp_dir},
-    fs::{create_dir, File},
+    env::current_dir,
+    fs::{create_dir_all, OpenOptions},
     io::{stdin, stdout, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::Output,
+    sync::Arc,
 };
 
 use crossterm::{terminal::SetTitle, QueueableCommand};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    fmt,
+    prelude::*,
+};
+
+use crate::{
+    binary_version::BinaryVersion,
+    config::{Config, LogConfig, LogFormat},
+    discovery::{self, GAME_EXECUTABLE},
+    Error,
+};
 
-use crate::{binary_version::BinaryVersion, Error, TEMP_DIR_NAME};
+/// Target used for the raw stdout/stderr of hpatchz and 7z, only written to the log file
+pub const TOOL_OUTPUT_TARGET: &str = "tool_output";
 
-pub fn init_tracing() {
+pub const LOG_FILE_NAME: &str = "hdiff-apply.log";
+
+/// Set up console logging and, unless disabled, a timestamped log file which defaults
+/// to the game directory. Returns the path of the log file
+pub fn init_tracing(log: &LogConfig, game_path: &Path) -> Result<Option<PathBuf>, Error> {
     #[cfg(target_os = "windows")]
     let _ = ansi_term::enable_ansi_support();
 
-    tracing_subscriber::fmt()
-        .without_time()
-        .with_max_level(tracing::Level::DEBUG)
+    let console_level = LevelFilter::from(log.level);
+    // Tool output is noisy, only show it on the console at trace level
+    let tool_output_level = if console_level == LevelFilter::TRACE {
+        LevelFilter::TRACE
+    } else {
+        LevelFilter::OFF
+    };
+    let console_layer = fmt::layer().without_time().with_filter(
+        Targets::new()
+            .with_default(console_level)
+            .with_target(TOOL_OUTPUT_TARGET, tool_output_level),
+    );
+
+    let mut log_path = None;
+    let file_layer = if log.file {
+        let path = log
+            .path
+            .clone()
+            .unwrap_or_else(|| game_path.join(LOG_FILE_NAME));
+        let file = Arc::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        log_path = Some(path);
+
+        let file_level = console_level.max(LevelFilter::DEBUG);
+        let layer = match log.format {
+            LogFormat::Text => fmt::layer()
+                .with_ansi(false)
+                .with_writer(file)
+                .with_filter(file_level)
+                .boxed(),
+            LogFormat::Json => fmt::layer()
+                .json()
+                .with_writer(file)
+                .with_filter(file_level)
+                .boxed(),
+        };
+        Some(layer)
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
         .init();
+
+    Ok(log_path)
+}
+
+/// Record the output of a helper tool invocation in the log file
+pub fn log_tool_output(tool: &str, subject: &Path, output: &Output) {
+    tracing::debug!(
+        target: TOOL_OUTPUT_TARGET,
+        tool,
+        subject = %subject.display(),
+        status = %output.status,
+        stdout = %String::from_utf8_lossy(&output.stdout).trim(),
+        stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+    );
 }
 
 pub fn wait_for_input() {
@@ -26,30 +99,26 @@ pub fn wait_for_input() {
     stdin().read_line(&mut String::new()).unwrap();
 }
 
-pub fn get_hpatchz() -> Result<PathBuf, Error> {
-    let temp_path = temp_dir().join(TEMP_DIR_NAME).join("hpatchz.exe");
-
-    const HPATCHZ_BIN: &[u8] = include_bytes!("../bin/hpatchz.exe");
+/// The game directory from `--install`, the configuration, the current directory or,
+/// failing all of those, the only install discovery finds
+pub fn determine_game_path(config: &Config, install: Option<&str>) -> Result<PathBuf, Error> {
+    if let Some(selector) = install {
+        let installs = discovery::discover();
+        return Ok(discovery::select(&installs, selector)?.path.clone());
+    }
 
-    let mut file = File::create(&temp_path)?;
-    file.write_all(HPATCHZ_BIN)?;
+    if let Some(path) = &config.game_path {
+        return Ok(path.clone());
+    }
 
-    Ok(temp_path)
-}
+    let cwd = current_dir()?;
+    if cwd.join(GAME_EXECUTABLE).is_file() {
+        return Ok(cwd);
+    }
 
-pub fn determine_game_path(game_path: Option<String>) -> Result<PathBuf, Error> {
-    match game_path {
-        Some(path) => Ok(PathBuf::from(path)),
-        None => {
-            let cwd = current_dir()?;
-            let sr_exe = cwd.join("StarRail.exe");
-
-            if sr_exe.is_file() {
-                Ok(cwd)
-            } else {
-                Err(Error::PathNotFound(cwd.display().to_string()))
-            }
-        }
+    match discovery::single(&discovery::discover())? {
+        Some(install) => Ok(install.path.clone()),
+        None => Err(Error::PathNotFound(cwd.display().to_string())),
     }
 }
 
@@ -60,13 +129,13 @@ pub fn wait_for_confirmation(default_choice: bool) -> bool {
     stdin().read_line(&mut input).unwrap();
 
     match input.trim().to_lowercase().as_str() {
-        "y" | "yes" => return true,
-        "n" | "no" => return false,
-        _ => return default_choice,
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default_choice,
     }
 }
 
-pub fn get_update_archives(game_path: &PathBuf) -> Result<Vec<PathBuf>, Error> {
+pub fn get_update_archives(game_path: &Path) -> Result<Vec<PathBuf>, Error> {
     let mut paths = Vec::new();
     for entry in game_path.read_dir()? {
         let path = entry?.path();
@@ -89,10 +158,10 @@ pub fn get_update_archives(game_path: &PathBuf) -> Result<Vec<PathBuf>, Error> {
     Ok(paths)
 }
 
-pub fn get_and_create_temp_dir() -> Result<PathBuf, Error> {
-    let path = temp_dir().join(TEMP_DIR_NAME);
+pub fn get_and_create_temp_dir(config: &Config) -> Result<PathBuf, Error> {
+    let path = config.temp_dir.clone();
     if !path.exists() {
-        create_dir(&path)?;
+        create_dir_all(&path)?;
     }
     Ok(path)
 }
@@ -112,15 +181,28 @@ pub fn set_console_title() -> Result<(), Error> {
     Ok(())
 }
 
-pub fn clean_temp_hdiff_data() -> Result<(), Error> {
-    let temp_path = temp_dir().join(TEMP_DIR_NAME);
+/// Total physical memory in bytes, only known on Linux
+pub fn total_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
 
-    for entry in temp_path.read_dir()? {
-        let path = entry?.path();
-        if path.is_dir() {
-            std::fs::remove_dir_all(path)?
-        }
+/// Human readable size, e.g. `1.50 GiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
 
-    Ok(())
-}
\ No newline at end of file
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
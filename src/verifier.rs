@@ -1,59 +1,151 @@
 use std::{
-    fs::File,
-    io::{Seek, SeekFrom},
-    path::Path,
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
 };
 
-use serde::Deserialize;
-use serde_json::Value;
+use rayon::prelude::*;
 
-use crate::Error;
+use crate::{
+    checksum::{self, Algorithm},
+    config::VerifyLevel,
+    hdiffmap::{self, DiffMap, PatchError},
+    paths::GameDir,
+    utils,
+};
+
+/// Why a source file can't be patched
+#[derive(Debug)]
+pub enum Problem {
+    Missing,
+    Unreadable(String),
+    Size { expected: u64, actual: u64 },
+    Md5 { expected: String, actual: String },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Unreadable(e) => write!(f, "can't be read: {}", e),
+            Problem::Size { expected, actual } => write!(
+                f,
+                "expected {} ({} bytes), found {} ({} bytes)",
+                utils::format_size(*expected),
+                expected,
+                utils::format_size(*actual),
+                actual
+            ),
+            Problem::Md5 { expected, actual } => {
+                write!(f, "expected MD5 {}, found {}", expected, actual)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Mismatch {
+    pub source_file_name: String,
+    pub source_path: PathBuf,
+    pub problem: Problem,
+}
 
-#[derive(Deserialize)]
-struct DiffMap {
-    source_file_name: String,
-    source_file_size: u64,
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Sources that were looked at
+    pub checked: usize,
+    pub mismatches: Vec<Mismatch>,
 }
 
+impl VerifyReport {
+    /// Resolved paths of every bad source
+    pub fn bad_sources(&self) -> HashSet<PathBuf> {
+        self.mismatches
+            .iter()
+            .map(|m| m.source_path.clone())
+            .collect()
+    }
+}
+
+/// Checks the sources of every hdiffmap.json entry against the sizes and hashes
+/// in the manifest before anything is modified
 pub struct Verifier<'a, 'b> {
-    game_path: &'a Path,
+    game_dir: &'a GameDir,
     hdiff_map_path: &'b Path,
 }
 
 impl<'a, 'b> Verifier<'a, 'b> {
-    pub fn new(game_path: &'a Path, hdiff_map_path: &'b Path) -> Self {
+    pub fn new(game_dir: &'a GameDir, hdiff_map_path: &'b Path) -> Self {
         Self {
-            game_path,
+            game_dir,
             hdiff_map_path,
         }
     }
 
-    fn load_diff_map(&self) -> Result<Vec<DiffMap>, Error> {
-        let data = std::fs::read_to_string(&self.hdiff_map_path)?;
-        let deserialized: Value = serde_json::from_str(&data).unwrap();
+    /// Check every entry and collect all the bad sources. Sources in `written_earlier`
+    /// are produced by an earlier package of the chain and can't be checked yet
+    pub fn verify(
+        &self,
+        level: VerifyLevel,
+        written_earlier: &HashSet<PathBuf>,
+    ) -> Result<VerifyReport, PatchError> {
+        let diff_map = match hdiffmap::load_diff_map(self.game_dir, self.hdiff_map_path) {
+            Ok(diff_map) => diff_map,
+            Err(PatchError::NotFound(_)) => return Ok(VerifyReport::default()),
+            Err(e) => return Err(e),
+        };
 
-        let diff_map = deserialized.get("diff_map").unwrap();
-        
-        Ok(serde_json::from_value(diff_map.clone()).unwrap())
-    }
+        let mut seen = HashSet::new();
+        let sources: Vec<&DiffMap> = diff_map
+            .iter()
+            .filter(|entry| !written_earlier.contains(&entry.source_path))
+            .filter(|entry| seen.insert(&entry.source_path))
+            .collect();
 
-    pub fn by_file_size(&self) -> Result<bool, Error> {
-        let hdiff_map = self.load_diff_map()?;
+        let mut mismatches: Vec<Mismatch> = sources
+            .par_iter()
+            .filter_map(|entry| {
+                check(entry, level).map(|problem| Mismatch {
+                    source_file_name: entry.source_file_name.clone(),
+                    source_path: entry.source_path.clone(),
+                    problem,
+                })
+            })
+            .collect();
+        mismatches.sort_by(|a, b| a.source_file_name.cmp(&b.source_file_name));
 
-        for diff_map in &hdiff_map {
-            let expected_size = diff_map.source_file_size;
-            let source_file_path = self.game_path.join(&diff_map.source_file_name);
+        Ok(VerifyReport {
+            checked: sources.len(),
+            mismatches,
+        })
+    }
+}
 
-            let mut source_file = File::open(source_file_path)?;
-            let source_file_size = source_file.seek(SeekFrom::End(0))?;
+fn check(entry: &DiffMap, level: VerifyLevel) -> Option<Problem> {
+    let actual = match entry.source_path.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Some(Problem::Missing),
+        Err(e) => return Some(Problem::Unreadable(e.to_string())),
+    };
 
-            if source_file_size != expected_size {
-                return Ok(false);
-            }
-        }
+    // Packages without sizes in the manifest can only be checked for missing files
+    if entry.source_file_size != 0 && actual != entry.source_file_size {
+        return Some(Problem::Size {
+            expected: entry.source_file_size,
+            actual,
+        });
+    }
 
-        Ok(true)
+    if level != VerifyLevel::Md5 || entry.source_file_md5.is_empty() {
+        return None;
     }
 
-    // Could also verify by MD5 but it will take a lot of time
+    match checksum::hash_file(&entry.source_path, Algorithm::Md5) {
+        Ok(actual) if actual.eq_ignore_ascii_case(&entry.source_file_md5) => None,
+        Ok(actual) => Some(Problem::Md5 {
+            expected: entry.source_file_md5.to_lowercase(),
+            actual,
+        }),
+        Err(e) => Some(Problem::Unreadable(e.to_string())),
+    }
 }
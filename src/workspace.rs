@@ -0,0 +1,227 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all, File, OpenOptions, TryLockError},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rand::{distr::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::tools::{self, ToolError};
+
+const RUN_DIR_PREFIX: &str = "run-";
+const OWNER_LOCK_NAME: &str = "owner.lock";
+pub const INSTALL_LOCK_NAME: &str = ".hdiff-apply.lock";
+
+/// Workspaces younger than this may not have locked their owner lock yet
+const CREATION_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("Another hdiff-apply (pid {1}) is already updating {0}")]
+    InstallLocked(String, String),
+    #[error("Failed to lock {0}: {1}")]
+    Io(String, #[source] std::io::Error),
+    #[error(transparent)]
+    PrivateDir(#[from] ToolError),
+}
+
+/// Take an exclusive lock on `path` without waiting. `Ok(None)` means another
+/// process holds it
+fn try_lock_file(path: &Path) -> Result<Option<File>, LockError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| LockError::Io(path.display().to_string(), e))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(LockError::Io(path.display().to_string(), e)),
+    }
+}
+
+/// Whether `path` changed within the creation grace period, or its age is unknown
+fn is_recent(path: &Path) -> bool {
+    match path.metadata().and_then(|m| m.modified()) {
+        Ok(modified) => modified.elapsed().map_or(true, |age| age < CREATION_GRACE),
+        Err(_) => true,
+    }
+}
+
+fn write_pid(file: &mut File) {
+    let _ = file
+        .set_len(0)
+        .and_then(|_| write!(file, "{}", std::process::id()))
+        .and_then(|_| file.flush());
+}
+
+/// Private temp directory of this run. It is locked for as long as the run is alive,
+/// which lets later runs tell abandoned workspaces from ones still in use
+pub struct Workspace {
+    path: PathBuf,
+    lock: Option<File>,
+}
+
+impl Workspace {
+    pub fn create(temp_root: &Path) -> Result<Self, LockError> {
+        let rnd_name: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        let path = temp_root.join(format!(
+            "{}{}-{}",
+            RUN_DIR_PREFIX,
+            std::process::id(),
+            rnd_name
+        ));
+        // The temp root may be shared with other users, the run directory never is, or they
+        // could swap the extracted manifests between their check and the update
+        create_dir_all(temp_root).map_err(|e| LockError::Io(temp_root.display().to_string(), e))?;
+        tools::create_private_dir(&path)?;
+
+        let mut lock = try_lock_file(&path.join(OWNER_LOCK_NAME))?.ok_or_else(|| {
+            LockError::Io(
+                path.display().to_string(),
+                std::io::Error::new(ErrorKind::WouldBlock, "workspace is already in use"),
+            )
+        })?;
+        write_pid(&mut lock);
+
+        Ok(Self {
+            path,
+            lock: Some(lock),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove workspaces of earlier runs whose process is gone. Directories from
+    /// versions before workspaces had owners are removed as well
+    pub fn clean_stale(temp_root: &Path) {
+        let Ok(entries) = temp_root.read_dir() else {
+            return;
+        };
+
+        for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+            if !path.is_dir() {
+                continue;
+            }
+
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let owner_lock = path.join(OWNER_LOCK_NAME);
+            let stale = if name.starts_with(RUN_DIR_PREFIX) {
+                // The owner creates its lock file before it can lock it, leave young
+                // workspaces alone so that moment isn't mistaken for an abandoned run
+                if is_recent(&path) {
+                    continue;
+                }
+                // Locks are released by the OS when their process exits, however it ends
+                match owner_lock.exists().then(|| try_lock_file(&owner_lock)) {
+                    None => true,
+                    Some(Ok(Some(lock))) => {
+                        drop(lock);
+                        true
+                    }
+                    Some(_) => false,
+                }
+            } else {
+                name.starts_with("hdiff_")
+            };
+
+            if stale {
+                match remove_dir_all(&path) {
+                    Ok(()) => tracing::debug!("Removed stale workspace {}", path.display()),
+                    Err(e) => tracing::warn!("Failed to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        // Windows won't delete the directory while the lock file is open
+        drop(self.lock.take());
+        if let Err(e) = remove_dir_all(&self.path) {
+            tracing::debug!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Lock the file at `path` for the install in `game_path`, naming the pid of the process
+/// holding it otherwise
+fn lock_install(game_path: &Path, path: &Path) -> Result<File, LockError> {
+    match try_lock_file(path)? {
+        Some(mut file) => {
+            write_pid(&mut file);
+            Ok(file)
+        }
+        None => {
+            let mut pid = String::new();
+            let _ = File::open(path).and_then(|mut f| f.read_to_string(&mut pid));
+            let pid = if pid.trim().is_empty() {
+                "unknown".to_string()
+            } else {
+                pid.trim().to_string()
+            };
+
+            Err(LockError::InstallLocked(
+                game_path.display().to_string(),
+                pid,
+            ))
+        }
+    }
+}
+
+/// Lock file in the per-user lock directory named after the canonical path of the install,
+/// it guards installs that must not be written to
+fn keyed_lock(game_path: &Path) -> Result<File, LockError> {
+    let canonical = game_path
+        .canonicalize()
+        .map_err(|e| LockError::Io(game_path.display().to_string(), e))?;
+    let key = format!(
+        "{:x}",
+        Sha256::digest(canonical.to_string_lossy().as_bytes())
+    );
+
+    lock_install(
+        game_path,
+        &tools::lock_dir()?.join(format!("{}.lock", &key[..16])),
+    )
+}
+
+/// Locks that keep two runs from using the same install while one of them changes it.
+/// Runs of other users are only kept out by the lock file in the game directory
+pub struct InstallLock {
+    _files: Vec<File>,
+}
+
+impl InstallLock {
+    /// Lock an install that is about to be modified
+    pub fn acquire(game_path: &Path) -> Result<Self, LockError> {
+        let keyed = keyed_lock(game_path)?;
+        let file = lock_install(game_path, &game_path.join(INSTALL_LOCK_NAME))?;
+
+        Ok(Self {
+            _files: vec![keyed, file],
+        })
+    }
+
+    /// Lock an install that is only read, like the source of `--output-dir`. Nothing is
+    /// written into it, the lock lives in the per-user lock directory only
+    pub fn acquire_source(game_path: &Path) -> Result<Self, LockError> {
+        Ok(Self {
+            _files: vec![keyed_lock(game_path)?],
+        })
+    }
+}
@@ -0,0 +1,334 @@
+#![cfg(unix)]
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use serde_json::json;
+
+const BINARY_VERSION_PATH: &str = "StarRail_Data/StreamingAssets/BinaryVersion.bytes";
+
+/// Stand-ins for the helpers, 7z is backed by tar and a patch is the new content behind
+/// the checksum of the file it applies to
+const SEVENZIP: &str = r#"#!/bin/sh
+[ $# -eq 0 ] && { echo "7-Zip (z) 23.01 (x64) : test stand-in"; exit 0; }
+cmd=$1; shift
+case $cmd in
+l)
+    echo "----------"
+    tar -tf "$2" | while read -r name; do
+        case $name in
+        */) printf 'Path = %s\nFolder = +\nSize = 0\n\n' "${name%/}" ;;
+        *) printf 'Path = %s\nFolder = -\nSize = %s\n\n' "$name" "$(tar -xOf "$2" "$name" | wc -c)" ;;
+        esac
+    done ;;
+t) tar -tf "$1" > /dev/null ;;
+x|e)
+    archive=$1; shift
+    out=.; excludes=; names=
+    for arg in "$@"; do
+        case $arg in
+        -o*) out=${arg#-o} ;;
+        -x!*) excludes="$excludes --exclude=${arg#-x!}" ;;
+        -*) ;;
+        *) names="$names $arg" ;;
+        esac
+    done
+    mkdir -p "$out"
+    if [ "$cmd" = e ]; then
+        for name in $names; do
+            tar -xOf "$archive" "$name" > "$out/${name##*/}" 2> /dev/null || rm -f "$out/${name##*/}"
+        done
+    else
+        tar -xf "$archive" -C "$out" $excludes $names
+    fi ;;
+a) tar -cf "$2" * ;;
+*) exit 2 ;;
+esac
+"#;
+
+const HPATCHZ: &str = r#"#!/bin/sh
+[ $# -eq 0 ] && { echo "HDiffPatch::hpatchz v4.6.9"; exit 0; }
+[ "$(sed -n 2p "$2")" = "$(cksum < "$1")" ] || { echo "$1 doesn't match the patch" >&2; exit 1; }
+tail -n +3 "$2" > "$3"
+"#;
+
+const HDIFFZ: &str = r#"#!/bin/sh
+[ $# -eq 0 ] && { echo "HDiffPatch::hdiffz v4.6.3"; exit 0; }
+{ echo TESTDIFF; cksum < "$3"; cat "$4"; } > "$5"
+"#;
+
+struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let root = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("tools")).unwrap();
+
+        for (tool, script) in [("7z", SEVENZIP), ("hpatchz", HPATCHZ), ("hdiffz", HDIFFZ)] {
+            let path = root.join("tools").join(tool);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        Self { root }
+    }
+
+    fn tool(&self, name: &str) -> PathBuf {
+        self.root.join("tools").join(name)
+    }
+
+    /// Write an update package to `version` into `dir`. `patches` are (source, target,
+    /// old content, new content)
+    fn package(
+        &self,
+        dir: &Path,
+        version: &str,
+        files: &[(&str, &str)],
+        patches: &[(&str, &str, &str, &str)],
+        deleted: &[&str],
+    ) {
+        let staging = self.root.join(format!("staging-{version}"));
+        let _ = fs::remove_dir_all(&staging);
+
+        write(
+            &staging,
+            BINARY_VERSION_PATH,
+            &format!("Build-{version}-rel"),
+        );
+        for (name, content) in files {
+            write(&staging, name, content);
+        }
+
+        let mut diff_map = vec![];
+        for (source, target, old, new) in patches {
+            let old_path = self.root.join("old");
+            let new_path = self.root.join("new");
+            fs::write(&old_path, old).unwrap();
+            fs::write(&new_path, new).unwrap();
+
+            let patch_name = format!("{target}.hdiff");
+            let patch_path = staging.join(&patch_name);
+            fs::create_dir_all(patch_path.parent().unwrap()).unwrap();
+            let status = Command::new(self.tool("hdiffz"))
+                .args(["-s", "-f"])
+                .args([&old_path, &new_path, &patch_path])
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            diff_map.push(json!({
+                "source_file_name": source,
+                "source_file_size": old.len(),
+                "target_file_name": target,
+                "target_file_size": new.len(),
+                "patch_file_name": patch_name,
+                "patch_file_size": fs::metadata(&patch_path).unwrap().len(),
+            }));
+        }
+        write(
+            &staging,
+            "hdiffmap.json",
+            &json!({ "diff_map": diff_map }).to_string(),
+        );
+        write(
+            &staging,
+            "deletefiles.txt",
+            &deleted
+                .iter()
+                .map(|name| format!("{name}\r\n"))
+                .collect::<String>(),
+        );
+
+        let status = Command::new(self.tool("7z"))
+            .current_dir(&staging)
+            .args(["a", "-t7z"])
+            .arg(dir.join(format!("{version}.7z")))
+            .arg("*")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn run(&self, game: &Path, args: &[&str], input: &str) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_hdiff-apply"))
+            .arg(game)
+            .arg("--sevenzip-path")
+            .arg(self.tool("7z"))
+            .arg("--hpatchz-path")
+            .arg(self.tool("hpatchz"))
+            .arg("--hdiffz-path")
+            .arg(self.tool("hdiffz"))
+            .arg("--temp-dir")
+            .arg(self.root.join("temp"))
+            .arg("--no-log-file")
+            .args(args)
+            .env("XDG_CACHE_HOME", self.root.join("cache"))
+            .env("XDG_CONFIG_HOME", self.root.join("config"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        assert!(
+            output.status.success(),
+            "hdiff-apply {:?} failed:\n{}\n{}",
+            args,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+}
+
+fn write(dir: &Path, name: &str, content: &str) {
+    let path = dir.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
+
+/// Copy the client in `from` to `to`, leaving out the packages
+fn copy_client(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        if entry.file_type().unwrap().is_dir() {
+            copy_client(&path, &to.join(entry.file_name()));
+        } else if path.extension().is_none_or(|e| e != "7z") {
+            fs::copy(&path, to.join(entry.file_name())).unwrap();
+        }
+    }
+}
+
+/// Every file and directory of the client with its content, without what hdiff-apply
+/// leaves behind
+fn snapshot(dir: &Path) -> BTreeMap<String, Option<String>> {
+    fn walk(root: &Path, dir: &Path, out: &mut BTreeMap<String, Option<String>>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path();
+            let name = path
+                .strip_prefix(root)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            if name.contains("hdiff-apply") || name.ends_with(".7z") {
+                continue;
+            }
+
+            if entry.file_type().unwrap().is_dir() {
+                out.insert(name, None);
+                walk(root, &path, out);
+            } else {
+                out.insert(name, Some(fs::read_to_string(&path).unwrap()));
+            }
+        }
+    }
+
+    let mut out = BTreeMap::new();
+    walk(dir, dir, &mut out);
+    out
+}
+
+#[test]
+fn squashed_chain_matches_sequential_updates() {
+    let fixture = Fixture::new("squash-chain");
+    let base = fixture.root.join("base");
+
+    write(&base, BINARY_VERSION_PATH, "Build-10.3.0-rel");
+    for (name, content) in [
+        ("StarRail.exe", "exe"),
+        ("a.txt", "a0"),
+        ("r.txt", "r0"),
+        ("d.txt", "d"),
+        ("olddir/x.txt", "x"),
+        ("u.txt", "u0"),
+        ("keep.txt", "k"),
+    ] {
+        write(&base, name, content);
+    }
+
+    let packages = fixture.root.join("packages");
+    fs::create_dir_all(&packages).unwrap();
+    fixture.package(
+        &packages,
+        "10.3.1",
+        &[("new1.txt", "n1"), ("sub/n.txt", "sn")],
+        &[
+            ("a.txt", "a.txt", "a0", "a1"),
+            ("r.txt", "r2.txt", "r0", "r1"),
+        ],
+        &["d.txt", "olddir"],
+    );
+    // a.txt is patched a second time and new1.txt was added by the package before
+    fixture.package(
+        &packages,
+        "10.3.2",
+        &[("new2.txt", "n2"), ("u.txt", "u1")],
+        &[
+            ("a.txt", "a.txt", "a1", "a2"),
+            ("new1.txt", "new1.txt", "n1", "n1b"),
+        ],
+        &["sub/n.txt"],
+    );
+
+    let game = fixture.root.join("game");
+    copy_client(&base, &game);
+    for package in ["10.3.1.7z", "10.3.2.7z"] {
+        fs::copy(packages.join(package), game.join(package)).unwrap();
+    }
+
+    // --check applies the squashed package to a second copy and compares both results
+    let squashed = fixture.root.join("squashed");
+    let output = fixture.run(
+        &game,
+        &["squash", squashed.to_str().unwrap(), "--check"],
+        "",
+    );
+    let log = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        log.contains("matches applying the packages one by one"),
+        "{log}"
+    );
+    assert_eq!(snapshot(&game), snapshot(&base), "squash modified the game");
+
+    let sequential = fixture.root.join("sequential");
+    copy_client(&game, &sequential);
+    for package in ["10.3.1.7z", "10.3.2.7z"] {
+        fs::copy(packages.join(package), sequential.join(package)).unwrap();
+    }
+    fixture.run(&sequential, &[], "y\n\n");
+
+    let single = fixture.root.join("single");
+    copy_client(&base, &single);
+    let archive = squashed.join("squash_10.3.0_to_10.3.2.7z");
+    fs::copy(&archive, single.join("squash.7z")).unwrap();
+    fixture.run(&single, &[], "y\n\n");
+
+    let expected = snapshot(&sequential);
+    assert_eq!(expected["a.txt"].as_deref(), Some("a2"));
+    assert_eq!(expected["new1.txt"].as_deref(), Some("n1b"));
+    assert!(!expected.contains_key("d.txt") && !expected.contains_key("olddir"));
+    assert_eq!(snapshot(&single), expected);
+}